@@ -0,0 +1,187 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! An atomic cell holding a thread ID.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use id::ThreadId;
+
+/// Zero is never a valid `ThreadId`, so it stands for "no thread".
+const RAW_NONE: u64 = 0;
+
+fn encode(id: Option<ThreadId>) -> u64 {
+    match id {
+        Some(id) => id.as_u64(),
+        None => RAW_NONE,
+    }
+}
+
+fn decode(raw: u64) -> Option<ThreadId> {
+    ThreadId::try_from_raw(raw)
+}
+
+/// A thread ID, or no thread, that can be shared between threads.
+///
+/// This is useful to track which thread owns a lock or a single-owner
+/// resource. It holds the same values as `current()`, and `None` is stored as
+/// zero, a value that no thread ID takes, so all operations are lock-free
+/// operations on a single `AtomicU64`.
+pub struct AtomicThreadId {
+    raw: AtomicU64,
+}
+
+impl AtomicThreadId {
+    /// The sentinel for no thread, which is stored as zero.
+    ///
+    /// This is `None`; the constant spells out the intent in calls such as
+    /// `owner.compare_exchange(AtomicThreadId::NONE, Some(current()), ..)`.
+    pub const NONE: Option<ThreadId> = None;
+
+    /// Creates a new atomic thread ID.
+    #[inline]
+    pub const fn new(id: Option<ThreadId>) -> AtomicThreadId {
+        let raw = match id {
+            Some(id) => id.as_u64(),
+            None => RAW_NONE,
+        };
+        AtomicThreadId {
+            raw: AtomicU64::new(raw),
+        }
+    }
+
+    /// Creates a new atomic thread ID that holds no thread.
+    #[inline]
+    pub const fn none() -> AtomicThreadId {
+        AtomicThreadId::new(None)
+    }
+
+    /// Loads the thread ID.
+    #[inline]
+    pub fn load(&self, order: Ordering) -> Option<ThreadId> {
+        decode(self.raw.load(order))
+    }
+
+    /// Stores a thread ID.
+    #[inline]
+    pub fn store(&self, id: Option<ThreadId>, order: Ordering) {
+        self.raw.store(encode(id), order)
+    }
+
+    /// Stores a thread ID, returning the previous one.
+    #[inline]
+    pub fn swap(&self, id: Option<ThreadId>, order: Ordering) -> Option<ThreadId> {
+        decode(self.raw.swap(encode(id), order))
+    }
+
+    /// Stores `new` if the current value is `current`.
+    ///
+    /// Returns the previous value, in `Ok` if it was `current`, and in `Err`
+    /// otherwise. The orderings are as for `AtomicU64::compare_exchange`.
+    #[inline]
+    pub fn compare_exchange(
+        &self,
+        current: Option<ThreadId>,
+        new: Option<ThreadId>,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<Option<ThreadId>, Option<ThreadId>> {
+        self.raw
+            .compare_exchange(encode(current), encode(new), success, failure)
+            .map(decode)
+            .map_err(decode)
+    }
+
+    /// Stores the ID of the calling thread, if no thread is stored.
+    ///
+    /// On failure, returns the thread that is stored, which may be the calling
+    /// thread itself. A successful claim has acquire ordering.
+    #[inline]
+    pub fn try_claim(&self) -> Result<(), ThreadId> {
+        match self.compare_exchange(
+            None,
+            Some(::current()),
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        ) {
+            Ok(..) => Ok(()),
+            Err(owner) => Err(owner.expect("failed claim should have an owner")),
+        }
+    }
+
+    /// Clears the stored ID, if it is the ID of the calling thread.
+    ///
+    /// Returns whether the calling thread was stored. A successful release
+    /// has release ordering.
+    #[inline]
+    pub fn release(&self) -> bool {
+        self.compare_exchange(
+            Some(::current()),
+            None,
+            Ordering::Release,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+    }
+
+    /// Returns whether the stored ID is the ID of the calling thread.
+    #[inline]
+    pub fn is_current(&self) -> bool {
+        self.load(Ordering::Relaxed) == Some(::current())
+    }
+}
+
+impl Default for AtomicThreadId {
+    fn default() -> AtomicThreadId {
+        AtomicThreadId::none()
+    }
+}
+
+impl From<Option<ThreadId>> for AtomicThreadId {
+    fn from(id: Option<ThreadId>) -> AtomicThreadId {
+        AtomicThreadId::new(id)
+    }
+}
+
+impl fmt::Debug for AtomicThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.load(Ordering::Relaxed), f)
+    }
+}
+
+#[test]
+fn atomic_thread_id_tracks_single_owner() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let owner = Arc::new(AtomicThreadId::none());
+    assert_eq!(owner.load(Ordering::SeqCst), AtomicThreadId::NONE);
+    assert_eq!(owner.try_claim(), Ok(()));
+    assert!(owner.is_current());
+    assert_eq!(owner.try_claim(), Err(::current()));
+
+    let other = owner.clone();
+    let (claim, release) = thread::spawn(move || (other.try_claim(), other.release()))
+        .join()
+        .unwrap();
+    assert_eq!(claim, Err(::current()));
+    assert!(!release);
+
+    assert!(owner.release());
+    assert!(!owner.release());
+    assert_eq!(owner.swap(Some(::current()), Ordering::SeqCst), None);
+    assert_eq!(
+        owner.compare_exchange(
+            AtomicThreadId::NONE,
+            AtomicThreadId::NONE,
+            Ordering::SeqCst,
+            Ordering::SeqCst
+        ),
+        Err(Some(::current()))
+    );
+}
@@ -0,0 +1,242 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Sources of thread IDs.
+//!
+//! The value of `get()` and `current()` comes from one of the sources in this
+//! module, chosen at compile time. By default it is the native ID of the
+//! platform: `pthread_self()` on Unix, `GetCurrentThreadId()` on Windows, the
+//! process ID on Redox, and the standard library thread ID elsewhere. Cargo
+//! features select a different source:
+//!
+//!  * `backend-counter`: `Counter`, which never reuses IDs.
+//!  * `backend-std`: `StdThreadId`, which never reuses IDs and matches
+//!    `std::thread::ThreadId`.
+//!  * `backend-gettid`: `Gettid`, the kernel thread ID, on Linux and Android.
+//!  * `backend-pthread`: `PthreadSelf`, on Unix.
+//!
+//! If several features are enabled, for example by different crates in the
+//! dependency graph, the first one in this list wins. Features for sources
+//! that the platform does not have are ignored. `backend_name()` tells which
+//! source is in use.
+//!
+//! Every source can also be queried directly through `ThreadIdSource`, whether
+//! it is selected or not.
+
+/// A source of thread IDs.
+pub trait ThreadIdSource {
+    /// The name of the source, as returned by `backend_name()`.
+    const NAME: &'static str;
+
+    /// Whether the ID of an exited thread may be handed out to a later thread.
+    const REUSES_IDS: bool;
+
+    /// Returns the ID of the calling thread, which is never zero.
+    fn current() -> u64;
+}
+
+/// The `pthread_t` of the thread, as returned by `pthread_self()`.
+///
+/// The value is an address on most platforms. It is reused after a thread
+/// exits, and a forked child has the same value as the forking thread.
+#[cfg(unix)]
+#[derive(Copy, Clone, Debug)]
+pub struct PthreadSelf;
+
+#[cfg(unix)]
+impl ThreadIdSource for PthreadSelf {
+    const NAME: &'static str = "pthread_self";
+    const REUSES_IDS: bool = true;
+
+    #[inline]
+    fn current() -> u64 {
+        unsafe { libc::pthread_self() as usize as u64 }
+    }
+}
+
+/// The kernel thread ID, as returned by `gettid()`.
+///
+/// This is the same value as `os_tid()`. It is reused after a thread exits,
+/// and differs between a forked child and the forking thread.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[derive(Copy, Clone, Debug)]
+pub struct Gettid;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl ThreadIdSource for Gettid {
+    const NAME: &'static str = "gettid";
+    const REUSES_IDS: bool = true;
+
+    #[inline]
+    fn current() -> u64 {
+        ::os_tid() as u64
+    }
+}
+
+/// The thread ID returned by `GetCurrentThreadId()`.
+#[cfg(windows)]
+#[derive(Copy, Clone, Debug)]
+pub struct GetCurrentThreadId;
+
+#[cfg(windows)]
+impl ThreadIdSource for GetCurrentThreadId {
+    const NAME: &'static str = "GetCurrentThreadId";
+    const REUSES_IDS: bool = true;
+
+    #[inline]
+    fn current() -> u64 {
+        unsafe { winapi::um::processthreadsapi::GetCurrentThreadId() as u64 }
+    }
+}
+
+/// The process ID, which is separate for every thread on Redox.
+#[cfg(target_os = "redox")]
+#[derive(Copy, Clone, Debug)]
+pub struct RedoxPid;
+
+#[cfg(target_os = "redox")]
+impl ThreadIdSource for RedoxPid {
+    const NAME: &'static str = "getpid";
+    const REUSES_IDS: bool = true;
+
+    #[inline]
+    fn current() -> u64 {
+        syscall::getpid().unwrap() as u64
+    }
+}
+
+/// The integer value of `std::thread::ThreadId`.
+///
+/// The standard library never reuses these IDs within a process.
+#[derive(Copy, Clone, Debug)]
+pub struct StdThreadId;
+
+impl ThreadIdSource for StdThreadId {
+    const NAME: &'static str = "std";
+    const REUSES_IDS: bool = false;
+
+    #[inline]
+    fn current() -> u64 {
+        use std::cell::Cell;
+        use std::thread;
+
+        thread_local! {
+            static STD_ID: Cell<u64> = const { Cell::new(0) };
+        }
+
+        fn lookup() -> u64 {
+            ::std_id::to_u64(thread::current().id())
+        }
+
+        STD_ID
+            .try_with(|cached| {
+                if cached.get() == 0 {
+                    cached.set(lookup());
+                }
+                cached.get()
+            })
+            .unwrap_or_else(|_| lookup())
+    }
+}
+
+/// A counter managed by this crate, the same value as `serial()`.
+///
+/// IDs are never reused, and a forked child gets a new one.
+#[derive(Copy, Clone, Debug)]
+pub struct Counter;
+
+impl ThreadIdSource for Counter {
+    const NAME: &'static str = "counter";
+    const REUSES_IDS: bool = false;
+
+    #[inline]
+    fn current() -> u64 {
+        ::serial()
+    }
+}
+
+/// The native source of the platform, used when no backend feature is enabled.
+#[cfg(all(unix, not(target_os = "redox")))]
+pub type Native = PthreadSelf;
+
+/// The native source of the platform, used when no backend feature is enabled.
+#[cfg(windows)]
+pub type Native = GetCurrentThreadId;
+
+/// The native source of the platform, used when no backend feature is enabled.
+#[cfg(target_os = "redox")]
+pub type Native = RedoxPid;
+
+/// The native source of the platform, used when no backend feature is enabled.
+#[cfg(not(any(unix, windows, target_os = "redox")))]
+pub type Native = StdThreadId;
+
+#[cfg(feature = "backend-counter")]
+type Selected = Counter;
+
+#[cfg(all(not(feature = "backend-counter"), feature = "backend-std"))]
+type Selected = StdThreadId;
+
+#[cfg(all(
+    not(any(feature = "backend-counter", feature = "backend-std")),
+    feature = "backend-gettid",
+    any(target_os = "linux", target_os = "android")
+))]
+type Selected = Gettid;
+
+#[cfg(all(
+    not(any(
+        feature = "backend-counter",
+        feature = "backend-std",
+        all(
+            feature = "backend-gettid",
+            any(target_os = "linux", target_os = "android")
+        )
+    )),
+    feature = "backend-pthread",
+    unix
+))]
+type Selected = PthreadSelf;
+
+#[cfg(not(any(
+    feature = "backend-counter",
+    feature = "backend-std",
+    all(
+        feature = "backend-gettid",
+        any(target_os = "linux", target_os = "android")
+    ),
+    all(feature = "backend-pthread", unix)
+)))]
+type Selected = Native;
+
+/// Whether the selected source reuses the IDs of exited threads.
+pub(crate) const REUSES_IDS: bool = Selected::REUSES_IDS;
+
+/// Returns the raw ID of the calling thread from the selected source.
+#[inline]
+pub(crate) fn current() -> u64 {
+    Selected::current()
+}
+
+/// Returns the name of the source that `get()` and `current()` use.
+///
+/// This is the `NAME` of the selected `ThreadIdSource`, such as
+/// `"pthread_self"` or `"counter"`.
+pub fn backend_name() -> &'static str {
+    Selected::NAME
+}
+
+#[test]
+fn selected_backend_agrees_with_current() {
+    assert_eq!(current(), ::current().as_u64());
+    assert!(!backend_name().is_empty());
+
+    assert_eq!(StdThreadId::current(), StdThreadId::current());
+    assert_eq!(Counter::current(), ::serial());
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    assert_eq!(Gettid::current(), ::os_tid() as u64);
+}
@@ -0,0 +1,163 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Refreshing cached IDs in the child after `fork()`.
+//!
+//! After `fork()`, the child has a single thread, but it inherits the caches of
+//! the forking thread and the global state of all threads of the parent. The
+//! handlers registered here hold the global locks across the fork, so the state
+//! is consistent in the child, and then reset everything that no longer holds.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static FORK_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of times the process was forked from its ancestors.
+///
+/// The counter is incremented in the child after every `fork()` that happens
+/// once this crate has cached any ID. A caller that stores the value can tell
+/// that it now runs in a forked child when the value changes, and that every
+/// thread other than the calling one is gone. On platforms without `fork()`
+/// this is always zero.
+#[inline]
+pub fn fork_generation() -> u64 {
+    FORK_GENERATION.load(Ordering::Acquire)
+}
+
+#[cfg(unix)]
+mod handlers {
+    use std::cell::RefCell;
+    use std::sync::atomic::Ordering;
+    use std::sync::{MutexGuard, Once, RwLockWriteGuard};
+
+    use generation;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    use global;
+    use hooks;
+    use index;
+    use lock_order;
+    use registry;
+    use serial;
+    use thread_bound;
+
+    /// The global locks, held by the forking thread for the duration of
+    /// `fork()`. Locks are acquired in field order.
+    struct Held {
+        index: MutexGuard<'static, index::Allocator>,
+        generation: MutexGuard<'static, Option<generation::Incarnations>>,
+        registry: RwLockWriteGuard<'static, Option<registry::Entries>>,
+        thread_bound: MutexGuard<'static, Option<thread_bound::Queues>>,
+        // The lock order graph stays valid in the child, it only needs to be
+        // consistent.
+        #[cfg(debug_assertions)]
+        _lock_order: MutexGuard<'static, Option<lock_order::Graph>>,
+        // The remaining locks are never held while taking another lock, and
+        // their state is valid in the child as is.
+        _violation_handler: RwLockWriteGuard<'static, Option<lock_order::Handler>>,
+        _hooks: [RwLockWriteGuard<'static, Vec<hooks::Hook>>; 2],
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        _start_time: MutexGuard<'static, Option<(u32, u64)>>,
+    }
+
+    thread_local! {
+        static HELD: RefCell<Option<Held>> = const { RefCell::new(None) };
+    }
+
+    static REGISTER: Once = Once::new();
+
+    pub fn register() {
+        REGISTER.call_once(|| unsafe {
+            libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+        });
+    }
+
+    extern "C" fn prepare() {
+        let held = Held {
+            index: index::lock_for_fork(),
+            generation: generation::lock_for_fork(),
+            registry: registry::lock_for_fork(),
+            thread_bound: thread_bound::lock_for_fork(),
+            #[cfg(debug_assertions)]
+            _lock_order: lock_order::lock_for_fork(),
+            _violation_handler: lock_order::lock_handler_for_fork(),
+            _hooks: hooks::lock_for_fork(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            _start_time: global::lock_for_fork(),
+        };
+        // If the thread is being torn down, the locks are released right
+        // away, and the child keeps the state as it was.
+        let _ = HELD.try_with(|slot| *slot.borrow_mut() = Some(held));
+    }
+
+    extern "C" fn parent() {
+        let _ = HELD.try_with(|slot| slot.borrow_mut().take());
+    }
+
+    extern "C" fn child() {
+        super::FORK_GENERATION.fetch_add(1, Ordering::AcqRel);
+
+        // Clear the per-thread caches first; the backend and the registry need
+        // the new values.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        ::os_tid::after_fork_in_child();
+        serial::after_fork_in_child();
+
+        if let Ok(Some(mut held)) = HELD.try_with(|slot| slot.borrow_mut().take()) {
+            index::after_fork_in_child(&mut held.index);
+            generation::after_fork_in_child(&mut held.generation);
+            registry::after_fork_in_child(&mut held.registry);
+            thread_bound::after_fork_in_child(&mut held.thread_bound);
+        }
+    }
+}
+
+/// Registers the `fork()` handlers, if the platform has `fork()`.
+///
+/// This needs to be called before caching any ID.
+#[inline]
+pub(crate) fn register() {
+    #[cfg(unix)]
+    handlers::register();
+}
+
+#[cfg(unix)]
+#[test]
+fn forked_child_refreshes_cached_ids() {
+    // The test thread is new, and it does not register with the registry
+    // before the fork.
+    let parent_serial = ::serial();
+    let parent_index = ::index();
+    let parent_generation = fork_generation();
+
+    unsafe {
+        match libc::fork() {
+            0 => {
+                // In the child, avoid panicking into the test harness; report
+                // through the exit status instead.
+                let mut ok = fork_generation() == parent_generation + 1;
+                ok &= ::serial() != parent_serial;
+                ok &= ::index() == parent_index;
+                ok &= ::info(::current()).is_some();
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                {
+                    ok &= ::os_tid() as libc::pid_t == libc::getpid();
+                }
+                libc::_exit(if ok { 0 } else { 1 });
+            }
+            -1 => panic!("fork failed"),
+            pid => {
+                let mut status = 0;
+                assert_eq!(libc::waitpid(pid, &mut status, 0), pid);
+                assert!(libc::WIFEXITED(status));
+                assert_eq!(libc::WEXITSTATUS(status), 0);
+            }
+        }
+    }
+
+    assert_eq!(fork_generation(), parent_generation);
+    assert_eq!(::serial(), parent_serial);
+}
@@ -0,0 +1,252 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Generational thread IDs that detect reuse of the platform ID.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use fork;
+
+/// The latest thread seen for a given raw ID.
+pub(crate) struct Incarnation {
+    generation: u32,
+    alive: bool,
+}
+
+pub(crate) type Incarnations = HashMap<usize, Incarnation>;
+
+static INCARNATIONS: Mutex<Option<Incarnations>> = Mutex::new(None);
+
+fn with_incarnations<R, F: FnOnce(&mut Incarnations) -> R>(f: F) -> R {
+    let mut incarnations = INCARNATIONS.lock().unwrap();
+    f(incarnations.get_or_insert_with(HashMap::new))
+}
+
+fn register(incarnations: &mut Incarnations, raw: usize) -> GenerationalId {
+    let incarnation = incarnations.entry(raw).or_insert(Incarnation {
+        // Wraps to zero below for the first thread with this raw ID.
+        generation: u32::MAX,
+        alive: false,
+    });
+    incarnation.generation = incarnation.generation.wrapping_add(1);
+    incarnation.alive = true;
+    GenerationalId {
+        raw,
+        generation: incarnation.generation,
+    }
+}
+
+/// Marks the thread as exited, or forgets its raw ID if the backend never
+/// hands it out again.
+fn retire(incarnations: &mut Incarnations, id: GenerationalId, reuses_ids: bool) {
+    if let Some(incarnation) = incarnations.get_mut(&id.raw) {
+        if incarnation.generation != id.generation {
+            return;
+        }
+        if reuses_ids {
+            incarnation.alive = false;
+        } else {
+            // No later thread gets this raw ID, so there is no generation to
+            // continue from, and a missing entry is not live either. This
+            // keeps the map from growing with every thread.
+            incarnations.remove(&id.raw);
+        }
+    }
+}
+
+fn is_live(incarnations: &Incarnations, id: GenerationalId) -> bool {
+    match incarnations.get(&id.raw) {
+        Some(incarnation) => incarnation.alive && incarnation.generation == id.generation,
+        None => false,
+    }
+}
+
+pub(crate) fn lock_for_fork() -> MutexGuard<'static, Option<Incarnations>> {
+    INCARNATIONS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Marks all threads but the calling one as exited, because only the calling
+/// thread survives in a forked child.
+pub(crate) fn after_fork_in_child(incarnations: &mut Option<Incarnations>) {
+    let raw = ::backend::current() as usize;
+    let own = GENERATION
+        .try_with(|slot| {
+            // If the backend gave the thread a new ID in the child, it gets a
+            // new generational ID on its next call, and the old one is dead.
+            if slot.id.get().map(|id| id.raw) != Some(raw) {
+                slot.id.set(None);
+            }
+            slot.id.get()
+        })
+        .unwrap_or(None);
+    if let Some(incarnations) = incarnations.as_mut() {
+        let own_raw = own.map(|id| id.raw);
+        if ::backend::REUSES_IDS {
+            for (&raw, incarnation) in incarnations.iter_mut() {
+                incarnation.alive &= own_raw == Some(raw);
+            }
+        } else {
+            incarnations.retain(|&raw, _| own_raw == Some(raw));
+        }
+    }
+}
+
+struct ThreadGeneration {
+    id: Cell<Option<GenerationalId>>,
+}
+
+impl Drop for ThreadGeneration {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.get() {
+            with_incarnations(|incarnations| retire(incarnations, id, ::backend::REUSES_IDS));
+        }
+    }
+}
+
+thread_local! {
+    static GENERATION: ThreadGeneration = const {
+        ThreadGeneration { id: Cell::new(None) }
+    };
+}
+
+/// A thread ID paired with a generation that tells apart threads which the
+/// platform gave the same ID.
+///
+/// The platform reuses the value of `get()` after a thread exits. The
+/// generation is incremented every time a new thread with a previously seen
+/// `get()` value asks for its `GenerationalId`, so a cache keyed by `get()`
+/// can store the `GenerationalId` alongside its entries, and use
+/// `is_current_incarnation()` to find entries left behind by a dead thread.
+///
+/// With the `serde` feature, a `GenerationalId` serializes as a struct with the
+/// fields `raw` and `generation`, the values of `raw()` and `generation()`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GenerationalId {
+    raw: usize,
+    generation: u32,
+}
+
+impl GenerationalId {
+    /// Returns the generational ID of the calling thread.
+    pub fn current() -> GenerationalId {
+        if let Some(id) = GENERATION.with(|slot| slot.id.get()) {
+            return id;
+        }
+        // This registers the thread and runs first-seen hooks, which may call
+        // into this module or take other global locks, so it must not run
+        // under the lock.
+        let raw = ::get();
+        GENERATION.with(|slot| match slot.id.get() {
+            Some(id) => id,
+            None => {
+                fork::register();
+                let id = with_incarnations(|incarnations| register(incarnations, raw));
+                slot.id.set(Some(id));
+                id
+            }
+        })
+    }
+
+    /// Returns the raw thread ID, the value that `get()` returned.
+    #[inline]
+    pub fn raw(&self) -> usize {
+        self.raw
+    }
+
+    /// Returns the generation of the raw ID.
+    ///
+    /// This is zero for the first thread that got this raw ID, and it is
+    /// incremented for every later thread that got the same raw ID. It wraps
+    /// around after `u32::MAX`.
+    #[inline]
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Returns whether the thread that this ID belongs to is still alive.
+    ///
+    /// Returns false once the thread has exited, even if a new thread has not
+    /// yet taken over the raw ID.
+    pub fn is_current_incarnation(&self) -> bool {
+        with_incarnations(|incarnations| is_live(incarnations, *self))
+    }
+}
+
+#[test]
+fn generation_increments_when_raw_id_is_reused() {
+    let mut incarnations = HashMap::new();
+    let first = register(&mut incarnations, 42);
+    assert_eq!(first.generation(), 0);
+    assert!(is_live(&incarnations, first));
+
+    retire(&mut incarnations, first, true);
+    assert!(!is_live(&incarnations, first));
+
+    let second = register(&mut incarnations, 42);
+    assert_eq!(second.raw(), first.raw());
+    assert_eq!(second.generation(), 1);
+    assert!(is_live(&incarnations, second));
+    assert!(!is_live(&incarnations, first));
+
+    // Backends that never reuse IDs do not keep retired entries around.
+    let other = register(&mut incarnations, 43);
+    retire(&mut incarnations, other, false);
+    assert!(!is_live(&incarnations, other));
+    assert!(!incarnations.contains_key(&43));
+}
+
+#[test]
+fn exited_thread_is_not_current_incarnation() {
+    use std::thread;
+
+    let main_id = GenerationalId::current();
+    assert_eq!(main_id, GenerationalId::current());
+    assert_eq!(main_id.raw(), ::get());
+    assert!(main_id.is_current_incarnation());
+
+    let other_id = thread::spawn(GenerationalId::current).join().unwrap();
+    assert!(!other_id.is_current_incarnation());
+}
+
+#[test]
+fn first_seen_hooks_can_ask_for_the_generational_id() {
+    use std::thread;
+
+    // The hook applies to every thread from now on, which is harmless.
+    ::on_thread_first_seen(|_| {
+        GenerationalId::current().is_current_incarnation();
+    });
+    let id = thread::spawn(|| {
+        let id = GenerationalId::current();
+        assert!(id.is_current_incarnation());
+        id
+    })
+    .join()
+    .unwrap();
+    assert!(!id.is_current_incarnation());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn generational_id_round_trips_through_serde() {
+    let id = GenerationalId {
+        raw: 7,
+        generation: 3,
+    };
+    let json = serde_json::to_string(&id).unwrap();
+    assert_eq!(json, r#"{"raw":7,"generation":3}"#);
+    assert_eq!(serde_json::from_str::<GenerationalId>(&json).unwrap(), id);
+
+    let bytes = bincode::serialize(&id).unwrap();
+    assert_eq!(bincode::deserialize::<GenerationalId>(&bytes).unwrap(), id);
+}
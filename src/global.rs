@@ -0,0 +1,207 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Thread IDs qualified with the process ID.
+
+use std::fmt;
+use std::process;
+use std::str::FromStr;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use id::{ParseThreadIdError, ThreadId};
+
+/// A thread ID that is unique across processes.
+///
+/// A `ThreadId` is only unique within a process. A `GlobalThreadId` pairs it
+/// with the process ID, and optionally with the start time of the process, to
+/// guard against the operating system reusing the process ID.
+///
+/// The text form is `pid:tid`, or `pid:tid@start_time` when the start time is
+/// known, with all numbers in decimal. It can be parsed back with `FromStr`.
+///
+/// With the `serde` feature, a `GlobalThreadId` serializes as a struct with the
+/// fields `pid`, `tid` and `start_time`, where `start_time` is optional.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct GlobalThreadId {
+    pid: u32,
+    tid: ThreadId,
+    start_time: Option<u64>,
+}
+
+impl GlobalThreadId {
+    /// Returns the global ID of the calling thread.
+    ///
+    /// On Linux this includes the start time of the process.
+    pub fn current() -> GlobalThreadId {
+        GlobalThreadId {
+            pid: process::id(),
+            tid: ::current(),
+            start_time: process_start_time(),
+        }
+    }
+
+    /// Pairs a thread ID with a process ID, without a start time.
+    pub fn new(pid: u32, tid: ThreadId) -> GlobalThreadId {
+        GlobalThreadId {
+            pid,
+            tid,
+            start_time: None,
+        }
+    }
+
+    /// Returns a copy of this ID with the given process start time.
+    pub fn with_start_time(self, start_time: u64) -> GlobalThreadId {
+        GlobalThreadId {
+            start_time: Some(start_time),
+            ..self
+        }
+    }
+
+    /// Returns the process ID.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Returns the thread ID within the process.
+    #[inline]
+    pub fn tid(&self) -> ThreadId {
+        self.tid
+    }
+
+    /// Returns the start time of the process, if known.
+    ///
+    /// On Linux this is the `starttime` field of `/proc/self/stat`, the time
+    /// the process started after system boot, in clock ticks.
+    #[inline]
+    pub fn start_time(&self) -> Option<u64> {
+        self.start_time
+    }
+}
+
+impl fmt::Display for GlobalThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.pid, self.tid)?;
+        if let Some(start_time) = self.start_time {
+            write!(f, "@{}", start_time)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for GlobalThreadId {
+    type Err = ParseThreadIdError;
+
+    fn from_str(s: &str) -> Result<GlobalThreadId, ParseThreadIdError> {
+        let (ids, start_time) = match s.find('@') {
+            Some(at) => (&s[..at], Some(&s[at + 1..])),
+            None => (s, None),
+        };
+        let colon = ids.find(':').ok_or_else(ParseThreadIdError::new)?;
+        let pid = ids[..colon]
+            .parse()
+            .map_err(|_| ParseThreadIdError::new())?;
+        let id = GlobalThreadId::new(pid, ids[colon + 1..].parse()?);
+        match start_time {
+            Some(t) => t
+                .parse()
+                .map(|t| id.with_start_time(t))
+                .map_err(|_| ParseThreadIdError::new()),
+            None => Ok(id),
+        }
+    }
+}
+
+// The start time is cached together with the pid it belongs to, so a forked
+// child reads its own.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+static START_TIME: Mutex<Option<(u32, u64)>> = Mutex::new(None);
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn process_start_time() -> Option<u64> {
+    use std::fs;
+
+    let pid = process::id();
+    let mut cached = START_TIME.lock().unwrap_or_else(PoisonError::into_inner);
+    match *cached {
+        Some((cached_pid, start_time)) if cached_pid == pid => Some(start_time),
+        _ => {
+            let stat = fs::read_to_string("/proc/self/stat").ok()?;
+            let start_time = parse_start_time(&stat)?;
+            *cached = Some((pid, start_time));
+            Some(start_time)
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn lock_for_fork() -> MutexGuard<'static, Option<(u32, u64)>> {
+    START_TIME.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn process_start_time() -> Option<u64> {
+    None
+}
+
+/// Extracts the `starttime` field from the contents of `/proc/<pid>/stat`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn parse_start_time(stat: &str) -> Option<u64> {
+    ::status::stat_field(stat, 22)?.parse().ok()
+}
+
+#[test]
+fn global_thread_id_round_trips_through_strings() {
+    let id = GlobalThreadId::current();
+    assert_eq!(id.pid(), process::id());
+    assert_eq!(id.tid(), ::current());
+    assert_eq!(id.to_string().parse::<GlobalThreadId>(), Ok(id));
+
+    let tid = ::current();
+    let id = GlobalThreadId::new(42, tid);
+    assert_eq!(id.to_string(), format!("42:{}", tid));
+    assert_eq!(id.to_string().parse::<GlobalThreadId>(), Ok(id));
+    let id = id.with_start_time(7);
+    assert_eq!(id.to_string(), format!("42:{}@7", tid));
+    assert_eq!(id.to_string().parse::<GlobalThreadId>(), Ok(id));
+
+    assert!("42".parse::<GlobalThreadId>().is_err());
+    assert!("42:".parse::<GlobalThreadId>().is_err());
+    assert!(":1".parse::<GlobalThreadId>().is_err());
+    assert!("42:1@".parse::<GlobalThreadId>().is_err());
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn start_time_is_parsed_after_command_name() {
+    let stat = "1234 (a (b) c) S 1 1234 1234 0 -1 4194560 100 0 0 0 5 3 0 0 \
+                20 0 1 0 98765 12345678 200 18446744073709551615";
+    assert_eq!(parse_start_time(stat), Some(98765));
+    assert_eq!(parse_start_time("1234 (x) S 1 2"), None);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn global_thread_id_round_trips_through_serde() {
+    let tid = ::current();
+    let id = GlobalThreadId::new(42, tid).with_start_time(7);
+    let json = serde_json::to_string(&id).unwrap();
+    assert_eq!(
+        json,
+        format!(r#"{{"pid":42,"tid":{},"start_time":7}}"#, tid)
+    );
+    assert_eq!(serde_json::from_str::<GlobalThreadId>(&json).unwrap(), id);
+
+    let id = GlobalThreadId::current();
+    let bytes = bincode::serialize(&id).unwrap();
+    assert_eq!(bincode::deserialize::<GlobalThreadId>(&bytes).unwrap(), id);
+}
@@ -0,0 +1,100 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Callbacks that run when threads start and exit.
+
+use std::sync::{Arc, PoisonError, RwLock, RwLockWriteGuard};
+
+use id::ThreadId;
+
+pub(crate) type Hook = Arc<dyn Fn(ThreadId) + Send + Sync>;
+
+static FIRST_SEEN: RwLock<Vec<Hook>> = RwLock::new(Vec::new());
+static EXIT: RwLock<Vec<Hook>> = RwLock::new(Vec::new());
+
+fn add(hooks: &RwLock<Vec<Hook>>, hook: Hook) {
+    hooks
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(hook);
+}
+
+fn run(hooks: &RwLock<Vec<Hook>>, id: ThreadId) {
+    // Call the hooks without holding the lock, so they can add hooks too.
+    let hooks = hooks.read().unwrap_or_else(PoisonError::into_inner).clone();
+    for hook in hooks {
+        hook(id);
+    }
+}
+
+/// Registers a callback that runs on every thread when it is first seen.
+///
+/// A thread is first seen when it first calls `get()` or `current()`. The
+/// callback runs on that thread, after the thread is added to the registry,
+/// so `info()` returns its metadata. It only runs for threads that are first
+/// seen after the callback was registered.
+pub fn on_thread_first_seen<F: Fn(ThreadId) + Send + Sync + 'static>(f: F) {
+    add(&FIRST_SEEN, Arc::new(f));
+}
+
+/// Registers a callback that runs on every thread that was seen, when it exits.
+///
+/// The callback runs on the exiting thread from a thread-local destructor,
+/// before the thread is removed from the registry, so `info()` still returns
+/// its metadata. Other thread-local values may already have been destroyed at
+/// that point. A panic in the callback aborts the process.
+pub fn on_thread_exit<F: Fn(ThreadId) + Send + Sync + 'static>(f: F) {
+    add(&EXIT, Arc::new(f));
+}
+
+pub(crate) fn run_first_seen(id: ThreadId) {
+    run(&FIRST_SEEN, id);
+}
+
+pub(crate) fn run_exit(id: ThreadId) {
+    run(&EXIT, id);
+}
+
+#[cfg(unix)]
+pub(crate) fn lock_for_fork() -> [RwLockWriteGuard<'static, Vec<Hook>>; 2] {
+    let write =
+        |hooks: &'static RwLock<Vec<Hook>>| hooks.write().unwrap_or_else(PoisonError::into_inner);
+    [write(&FIRST_SEEN), write(&EXIT)]
+}
+
+#[test]
+fn hooks_run_on_first_seen_and_exit() {
+    use std::sync::Mutex;
+    use std::thread;
+
+    static EVENTS: Mutex<Vec<(&str, ThreadId, bool)>> = Mutex::new(Vec::new());
+
+    on_thread_first_seen(|id| {
+        let registered = ::info(id).is_some();
+        EVENTS.lock().unwrap().push(("first seen", id, registered));
+    });
+    on_thread_exit(|id| {
+        let registered = ::info(id).is_some();
+        EVENTS.lock().unwrap().push(("exit", id, registered));
+    });
+
+    let id = thread::spawn(|| {
+        let id = ::current();
+        // Only the first call counts.
+        ::current();
+        id
+    })
+    .join()
+    .unwrap();
+
+    // Other threads may have had the same ID before or after, but their
+    // events cannot interleave with those of the spawned thread.
+    let events = EVENTS.lock().unwrap();
+    let events: Vec<_> = events.iter().cloned().filter(|e| e.1 == id).collect();
+    let expected = [("first seen", id, true), ("exit", id, true)];
+    assert!(events.windows(2).any(|pair| pair == expected));
+}
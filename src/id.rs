@@ -0,0 +1,163 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! The `ThreadId` value type.
+
+use std::error::Error;
+use std::fmt::{self, Write};
+use std::num::NonZeroU64;
+use std::str::FromStr;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// An identifier that is unique to a thread.
+///
+/// A `ThreadId` holds the same value as `thread_id::get()`, but as a distinct
+/// type, so it cannot be confused with counters or indices. It can be formatted
+/// in decimal (`{}`) or hexadecimal (`{:x}`), and parsed back from either form.
+/// Hexadecimal output always has the `0x` prefix, with or without `#`, and
+/// hexadecimal input must have it too.
+///
+/// With the `serde` feature, a `ThreadId` serializes as its `as_u64()` value,
+/// and deserializing zero fails.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct ThreadId(NonZeroU64);
+
+impl ThreadId {
+    /// Wraps a raw ID, which is never zero for a live thread on any backend.
+    #[inline]
+    pub(crate) fn from_raw(raw: u64) -> ThreadId {
+        ThreadId(NonZeroU64::new(raw).expect("thread ID should not be zero"))
+    }
+
+    /// Wraps a raw ID, or returns `None` for zero.
+    #[inline]
+    pub(crate) fn try_from_raw(raw: u64) -> Option<ThreadId> {
+        NonZeroU64::new(raw).map(ThreadId)
+    }
+
+    /// Returns the ID as a 64-bit integer.
+    ///
+    /// The value is zero-extended from the platform ID, so it is the same
+    /// number that `thread_id::get()` returns, on every platform. It is never
+    /// zero.
+    #[inline]
+    pub const fn as_u64(self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl From<ThreadId> for u64 {
+    #[inline]
+    fn from(id: ThreadId) -> u64 {
+        id.as_u64()
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::LowerHex for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Without the prefix, the output would parse back as decimal.
+        let hex = format!("{:#x}", self.0.get());
+        let padding = f.width().unwrap_or(0).saturating_sub(hex.len());
+        // Like integers, zero padding goes between the prefix and the digits,
+        // and other padding aligns to the right by default.
+        if f.sign_aware_zero_pad() {
+            return write!(f, "0x{}{}", "0".repeat(padding), &hex[2..]);
+        }
+        let (before, after) = match f.align() {
+            Some(fmt::Alignment::Left) => (0, padding),
+            Some(fmt::Alignment::Center) => (padding / 2, (padding + 1) / 2),
+            _ => (padding, 0),
+        };
+        let fill = f.fill();
+        for _ in 0..before {
+            f.write_char(fill)?;
+        }
+        f.write_str(&hex)?;
+        for _ in 0..after {
+            f.write_char(fill)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ThreadId {
+    type Err = ParseThreadIdError;
+
+    fn from_str(s: &str) -> Result<ThreadId, ParseThreadIdError> {
+        let parsed = match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => s.parse::<u64>(),
+        };
+        parsed
+            .ok()
+            .and_then(ThreadId::try_from_raw)
+            .ok_or_else(ParseThreadIdError::new)
+    }
+}
+
+/// The error returned when parsing a thread ID fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseThreadIdError {
+    _private: (),
+}
+
+impl ParseThreadIdError {
+    pub(crate) fn new() -> ParseThreadIdError {
+        ParseThreadIdError { _private: () }
+    }
+}
+
+impl fmt::Display for ParseThreadIdError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid thread ID")
+    }
+}
+
+impl Error for ParseThreadIdError {}
+
+#[test]
+fn thread_id_round_trips_through_strings() {
+    let id = ::current();
+    assert_eq!(id.to_string().parse::<ThreadId>(), Ok(id));
+    assert_eq!(format!("{:#x}", id).parse::<ThreadId>(), Ok(id));
+    assert_eq!(format!("{:x}", id).parse::<ThreadId>(), Ok(id));
+    assert_eq!(format!("{:x}", ThreadId::from_raw(0x1234)), "0x1234");
+    assert_eq!(id.as_u64(), ::get() as u64);
+    assert!("0".parse::<ThreadId>().is_err());
+    assert!("0x".parse::<ThreadId>().is_err());
+    assert!("-1".parse::<ThreadId>().is_err());
+
+    // The prefix counts towards the width, as it does for integers.
+    let wide = ThreadId::from_raw(0x7f8134c41880);
+    assert_eq!(format!("{:018x}", wide), "0x00007f8134c41880");
+    assert_eq!(format!("{:20x}", wide), "      0x7f8134c41880");
+    assert_eq!(format!("{:*<16x}", wide), "0x7f8134c41880**");
+    assert_eq!(format!("{:04x}", wide), "0x7f8134c41880");
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn thread_id_round_trips_through_serde() {
+    let id = ThreadId::from_raw(42);
+    let json = serde_json::to_string(&id).unwrap();
+    assert_eq!(json, "42");
+    assert_eq!(serde_json::from_str::<ThreadId>(&json).unwrap(), id);
+    assert!(serde_json::from_str::<ThreadId>("0").is_err());
+
+    let bytes = bincode::serialize(&id).unwrap();
+    assert_eq!(bytes, 42u64.to_le_bytes());
+    assert_eq!(bincode::deserialize::<ThreadId>(&bytes).unwrap(), id);
+}
@@ -0,0 +1,191 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! All the IDs of the calling thread in one place.
+
+use std::fmt;
+use std::process;
+use std::thread;
+
+use backend;
+use id::ThreadId;
+
+/// Every ID of a thread that is available on the platform.
+///
+/// The `Display` form is a single line for crash reports and logs, such as
+/// `thread 1234 "main" (backend pthread_self, os_tid 42, pthread 0x4d2, std
+/// ThreadId(1), serial 1, index 0, pid 42)`. IDs that are not available are
+/// left out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadIdentity {
+    id: ThreadId,
+    backend: &'static str,
+    name: Option<String>,
+    os_tid: Option<u32>,
+    pthread: Option<u64>,
+    std_id: thread::ThreadId,
+    serial: u64,
+    index: Option<usize>,
+    pid: u32,
+}
+
+/// Returns every ID of the calling thread.
+///
+/// This does not assign the thread an `index()` if it does not have one yet.
+pub fn identity() -> ThreadIdentity {
+    let std_thread = thread::current();
+    ThreadIdentity {
+        id: ::current(),
+        backend: backend::backend_name(),
+        name: std_thread.name().map(String::from),
+        os_tid: os_tid(),
+        pthread: pthread(),
+        std_id: std_thread.id(),
+        serial: ::serial(),
+        index: ::index::assigned(),
+        pid: process::id(),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn os_tid() -> Option<u32> {
+    Some(::os_tid())
+}
+
+#[cfg(windows)]
+fn os_tid() -> Option<u32> {
+    use backend::ThreadIdSource;
+
+    Some(backend::GetCurrentThreadId::current() as u32)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+fn os_tid() -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn pthread() -> Option<u64> {
+    use backend::ThreadIdSource;
+
+    Some(backend::PthreadSelf::current())
+}
+
+#[cfg(not(unix))]
+fn pthread() -> Option<u64> {
+    None
+}
+
+impl ThreadIdentity {
+    /// Returns the ID of the thread, as `current()` returns it.
+    #[inline]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns the name of the backend that `id()` came from.
+    #[inline]
+    pub fn backend(&self) -> &'static str {
+        self.backend
+    }
+
+    /// Returns the name of the thread, if it has one.
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+
+    /// Returns the kernel thread ID: `os_tid()` on Linux and Android, and
+    /// `GetCurrentThreadId()` on Windows.
+    #[inline]
+    pub fn os_tid(&self) -> Option<u32> {
+        self.os_tid
+    }
+
+    /// Returns the value of `pthread_self()`, on Unix.
+    #[inline]
+    pub fn pthread(&self) -> Option<u64> {
+        self.pthread
+    }
+
+    /// Returns the standard library ID of the thread.
+    #[inline]
+    pub fn std_id(&self) -> thread::ThreadId {
+        self.std_id
+    }
+
+    /// Returns the `serial()` of the thread.
+    #[inline]
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// Returns the `index()` of the thread, if it was assigned one.
+    #[inline]
+    pub fn index(&self) -> Option<usize> {
+        self.index
+    }
+
+    /// Returns the ID of the process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+impl fmt::Display for ThreadIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread {}", self.id)?;
+        if let Some(ref name) = self.name {
+            write!(f, " {:?}", name)?;
+        }
+        write!(f, " (backend {}", self.backend)?;
+        if let Some(os_tid) = self.os_tid {
+            write!(f, ", os_tid {}", os_tid)?;
+        }
+        if let Some(pthread) = self.pthread {
+            write!(f, ", pthread {:#x}", pthread)?;
+        }
+        write!(f, ", std {:?}, serial {}", self.std_id, self.serial)?;
+        if let Some(index) = self.index {
+            write!(f, ", index {}", index)?;
+        }
+        write!(f, ", pid {})", self.pid)
+    }
+}
+
+#[test]
+fn identity_collects_all_ids() {
+    let identity = thread::Builder::new()
+        .name("identity-test".to_string())
+        .spawn(|| {
+            let before = identity();
+            assert_eq!(before.index(), None);
+            ::index();
+            identity()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    assert_eq!(identity.name(), Some("identity-test"));
+    assert_eq!(identity.backend(), ::backend_name());
+    assert_eq!(identity.pid(), process::id());
+    assert!(identity.index().is_some());
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    assert!(identity.os_tid().is_some());
+    #[cfg(unix)]
+    assert!(identity.pthread().is_some());
+
+    let text = identity.to_string();
+    assert!(text.starts_with(&format!(
+        "thread {} \"identity-test\" (backend ",
+        identity.id()
+    )));
+    assert!(text.contains(&format!(", serial {}", identity.serial())));
+    assert!(text.ends_with(&format!(", pid {})", identity.pid())));
+}
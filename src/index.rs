@@ -0,0 +1,192 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Dense, recycled thread indices.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use fork;
+
+pub(crate) struct Allocator {
+    /// Released indices, sorted in descending order so the lowest is last.
+    free: Vec<usize>,
+    /// The lowest index that has never been handed out.
+    next: usize,
+}
+
+impl Allocator {
+    fn allocate(&mut self) -> usize {
+        match self.free.pop() {
+            Some(index) => index,
+            None => {
+                let index = self.next;
+                self.next += 1;
+                index
+            }
+        }
+    }
+
+    fn release(&mut self, index: usize) {
+        let pos = match self.free.binary_search_by(|probe| index.cmp(probe)) {
+            Ok(..) => unreachable!("thread index {} released twice", index),
+            Err(pos) => pos,
+        };
+        self.free.insert(pos, index);
+    }
+}
+
+static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator {
+    free: Vec::new(),
+    next: 0,
+});
+
+static MAX_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Copy, Clone)]
+enum Slot {
+    Unassigned,
+    Assigned(usize),
+    Released,
+}
+
+/// Releases the index when the thread exits.
+struct Release;
+
+impl Drop for Release {
+    fn drop(&mut self) {
+        if let Slot::Assigned(index) = INDEX.with(Cell::get) {
+            INDEX.with(|slot| slot.set(Slot::Released));
+            ALLOCATOR.lock().unwrap().release(index);
+        }
+    }
+}
+
+thread_local! {
+    // Without a destructor, this can be read from any other destructor.
+    static INDEX: Cell<Slot> = const { Cell::new(Slot::Unassigned) };
+
+    static RELEASE: Release = const { Release };
+}
+
+/// Returns a small integer that is unique to the calling thread among all
+/// live threads.
+///
+/// The index is assigned on the first call from a thread, and it is the lowest
+/// index not in use by another live thread. When the thread exits, its index
+/// is released and may be handed out to a thread created later. This makes
+/// the index suitable for indexing per-thread arrays: all indices are below
+/// `max_index()`.
+///
+/// # Panics
+///
+/// Panics when called from a thread-local destructor after the calling thread
+/// has released its index, or after the point where it would release it if it
+/// had one.
+#[inline]
+pub fn index() -> usize {
+    match INDEX.with(Cell::get) {
+        Slot::Assigned(index) => index,
+        Slot::Unassigned => assign(),
+        Slot::Released => panic!("index() called after the thread released its index"),
+    }
+}
+
+#[cold]
+fn assign() -> usize {
+    // The index can only be released if the destructor of `RELEASE` runs.
+    RELEASE
+        .try_with(|_| ())
+        .expect("index() called on an exiting thread");
+    fork::register();
+    let index = ALLOCATOR.lock().unwrap().allocate();
+    MAX_INDEX.fetch_max(index + 1, Ordering::AcqRel);
+    INDEX.with(|slot| slot.set(Slot::Assigned(index)));
+    index
+}
+
+/// Returns the index of the calling thread if it has one, without assigning
+/// one.
+pub(crate) fn assigned() -> Option<usize> {
+    match INDEX.with(Cell::get) {
+        Slot::Assigned(index) => Some(index),
+        Slot::Unassigned | Slot::Released => None,
+    }
+}
+
+pub(crate) fn lock_for_fork() -> MutexGuard<'static, Allocator> {
+    ALLOCATOR.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Releases the indices of all threads but the calling one, which is the only
+/// thread that survives in a forked child.
+pub(crate) fn after_fork_in_child(allocator: &mut Allocator) {
+    let own = assigned();
+    allocator.free = (0..allocator.next)
+        .rev()
+        .filter(|&i| Some(i) != own)
+        .collect();
+}
+
+/// Returns the high-water mark of `index()`.
+///
+/// Every index handed out so far is less than this value, so it is the size
+/// that a table indexed by `index()` needs to have. It never decreases.
+#[inline]
+pub fn max_index() -> usize {
+    MAX_INDEX.load(Ordering::Acquire)
+}
+
+#[test]
+fn live_threads_have_distinct_indices() {
+    use std::sync::mpsc;
+    use std::thread;
+
+    let main_index = index();
+    assert!(main_index < max_index());
+
+    // Keep one thread alive while a second one starts, so the two must have
+    // distinct indices.
+    let (tx, rx) = mpsc::channel::<()>();
+    let (index_tx, index_rx) = mpsc::channel();
+    let first = thread::spawn(move || {
+        index_tx.send(index()).unwrap();
+        rx.recv().unwrap();
+    });
+    let first_index = index_rx.recv().unwrap();
+    let second_index = thread::spawn(index).join().unwrap();
+    assert!(first_index != main_index);
+    assert!(second_index != main_index);
+    assert!(second_index != first_index);
+    tx.send(()).unwrap();
+    first.join().unwrap();
+
+    assert!(max_index() > first_index.max(second_index));
+}
+
+#[test]
+fn released_index_is_reused() {
+    use std::thread;
+
+    // Other tests may run concurrently, so only the allocator itself can
+    // be checked deterministically.
+    let mut allocator = Allocator {
+        free: Vec::new(),
+        next: 0,
+    };
+    let a = allocator.allocate();
+    let b = allocator.allocate();
+    let c = allocator.allocate();
+    allocator.release(c);
+    allocator.release(a);
+    assert_eq!(allocator.allocate(), a);
+    assert_eq!(allocator.allocate(), c);
+    assert_eq!(allocator.allocate(), b + 2);
+
+    assert!(thread::spawn(index).join().unwrap() < max_index());
+}
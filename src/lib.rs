@@ -24,51 +24,122 @@
 //!
 //! handle.join().unwrap();
 //! ```
+//!
+//! Use `thread_id::current()` instead of `thread_id::get()` to get the same ID
+//! as a `ThreadId`, a distinct type that cannot be mixed up with other integers.
 
 #![warn(missing_docs)]
-#![feature(thread_id_value)]
+#![cfg_attr(
+    any(target_os = "switch", feature = "nightly"),
+    feature(thread_id_value)
+)]
 
 #[cfg(unix)]
 extern crate libc;
 
+#[cfg(feature = "log")]
+extern crate log;
+
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(all(test, feature = "serde"))]
+extern crate bincode;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+#[cfg(feature = "tracing")]
+extern crate tracing_core;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
+
+#[cfg(all(test, feature = "tracing"))]
+extern crate tracing;
+
 #[cfg(windows)]
 extern crate winapi;
 
 #[cfg(target_os = "redox")]
 extern crate syscall;
 
+mod atomic;
+pub mod backend;
+mod fork;
+mod generation;
+mod global;
+mod hooks;
+mod id;
+mod identity;
+mod index;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod list;
+pub mod local;
+pub mod lock_order;
+#[cfg(feature = "log")]
+mod logger;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod os_tid;
+mod per_thread;
+mod reentrant;
+mod registry;
+mod serial;
+mod spawn;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod status;
+mod std_id;
+mod thread_bound;
+#[cfg(feature = "tracing")]
+mod tracing_layer;
+
+pub use atomic::AtomicThreadId;
+pub use backend::{backend_name, ThreadIdSource};
+pub use fork::fork_generation;
+pub use generation::GenerationalId;
+pub use global::GlobalThreadId;
+pub use hooks::{on_thread_exit, on_thread_first_seen};
+pub use id::{ParseThreadIdError, ThreadId};
+pub use identity::{identity, ThreadIdentity};
+pub use index::{index, max_index};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use list::{list, LiveThread};
+pub use local::ThreadLocal;
+#[cfg(feature = "log")]
+pub use logger::{ThreadLogger, DEFAULT_LOG_PREFIX};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use os_tid::os_tid;
+pub use per_thread::{PerThread, ShardedCounter, ShardedHistogram};
+pub use reentrant::{ReentrantMutex, ReentrantMutexGuard};
+pub use registry::{
+    children_of, from_std, info, parent_of, set_tag, thread_handle, thread_tree, to_std,
+    ThreadInfo, ThreadTree,
+};
+pub use serial::serial;
+pub use spawn::{spawn, Builder, JoinHandle};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use status::{StatusError, ThreadState, ThreadStatus};
+pub use thread_bound::{drop_returned, DropPolicy, ThreadBound, WrongThreadError};
+#[cfg(feature = "tracing")]
+pub use tracing_layer::{HandOff, SpanThreads, ThreadEventFormat, ThreadFields, ThreadLayer};
+
 /// Returns a number that is unique to the calling thread.
 ///
 /// Calling this function twice from the same thread will return the same
 /// number. Calling this function from a different thread will return a
-/// different number.
+/// different number. Where the number comes from is chosen at compile time;
+/// see the `backend` module.
 #[inline]
 pub fn get() -> usize {
-    get_internal()
-}
-
-#[cfg(target_os = "switch")]
-fn get_internal() -> usize {
-    std::thread::current().id().as_u64().get() as usize
+    current().as_u64() as usize
 }
 
-#[cfg(unix)]
-#[inline]
-fn get_internal() -> usize {
-    unsafe { libc::pthread_self() as usize }
-}
-
-#[cfg(windows)]
-#[inline]
-fn get_internal() -> usize {
-    unsafe { winapi::um::processthreadsapi::GetCurrentThreadId() as usize }
-}
-
-#[cfg(target_os = "redox")]
+/// Returns the ID of the calling thread as a `ThreadId`.
+///
+/// This is the same value as `get()` returns, wrapped in a distinct type.
 #[inline]
-fn get_internal() -> usize {
-    // Each thread has a separate pid on Redox.
-    syscall::getpid().unwrap()
+pub fn current() -> ThreadId {
+    let id = ThreadId::from_raw(backend::current());
+    registry::touch(id);
+    id
 }
 
 #[test]
@@ -0,0 +1,125 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Enumerating the live threads of the process on Linux.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use id::ThreadId;
+use registry;
+
+/// A live thread of the current process, as listed by `list()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LiveThread {
+    os_tid: u32,
+    id: Option<ThreadId>,
+    name: Option<String>,
+}
+
+impl LiveThread {
+    /// Returns the kernel thread ID.
+    #[inline]
+    pub fn os_tid(&self) -> u32 {
+        self.os_tid
+    }
+
+    /// Returns the ID of the thread, if it is in the registry.
+    ///
+    /// Threads that never called `get()` or `current()`, such as threads
+    /// started by C libraries, are not in the registry.
+    #[inline]
+    pub fn id(&self) -> Option<ThreadId> {
+        self.id
+    }
+
+    /// Returns the name of the thread.
+    ///
+    /// This is the name in the registry for registered threads, and the
+    /// kernel's `comm` name, truncated to 15 bytes, for other threads.
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+}
+
+/// Returns all live threads of the current process, ordered by kernel TID.
+///
+/// The threads are read from `/proc/self/task`, so this includes threads that
+/// never called into this crate. Threads that exit while the list is being
+/// built may or may not be included.
+pub fn list() -> io::Result<Vec<LiveThread>> {
+    let mut registered: HashMap<u32, (ThreadId, Option<String>)> = registry::snapshot()
+        .into_iter()
+        .filter_map(|info| {
+            let name = info.name().map(String::from);
+            info.os_tid().map(|tid| (tid, (info.id(), name)))
+        })
+        .collect();
+
+    let mut threads = Vec::new();
+    for entry in fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let os_tid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        let thread = match registered.remove(&os_tid) {
+            Some((id, name)) => LiveThread {
+                os_tid,
+                id: Some(id),
+                name,
+            },
+            None => match fs::read_to_string(entry.path().join("comm")) {
+                Ok(comm) => LiveThread {
+                    os_tid,
+                    id: None,
+                    name: Some(comm.trim_end_matches('\n').to_string()),
+                },
+                // The thread exited after the directory was listed.
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            },
+        };
+        threads.push(thread);
+    }
+
+    threads.sort_by_key(|thread| thread.os_tid);
+    Ok(threads)
+}
+
+#[test]
+fn list_includes_registered_and_unregistered_threads() {
+    use std::sync::mpsc;
+    use std::thread;
+
+    let (tid_tx, tid_rx) = mpsc::channel();
+    let (exit_tx, exit_rx) = mpsc::channel::<()>();
+    let handle = thread::Builder::new()
+        .name("unregistered".to_string())
+        .spawn(move || {
+            // Only the kernel TID, which does not register the thread.
+            tid_tx.send(::os_tid()).unwrap();
+            exit_rx.recv().unwrap();
+        })
+        .unwrap();
+    let other_tid = tid_rx.recv().unwrap();
+
+    let own_id = ::current();
+    let threads = list().unwrap();
+    exit_tx.send(()).unwrap();
+    handle.join().unwrap();
+
+    let own = threads.iter().find(|t| t.os_tid() == ::os_tid()).unwrap();
+    assert_eq!(own.id(), Some(own_id));
+    assert_eq!(own.name(), thread::current().name());
+
+    let other = threads.iter().find(|t| t.os_tid() == other_tid).unwrap();
+    assert_eq!(other.id(), None);
+    assert_eq!(other.name(), Some("unregistered"));
+}
@@ -0,0 +1,428 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Per-object thread-local storage.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::vec;
+
+/// Bucket `i` holds the entries for indices `2^i - 1` up to `2^(i+1) - 1`, so
+/// buckets never need to grow, and the number of buckets bounds the index.
+const BUCKETS: usize = usize::BITS as usize;
+
+struct Entry<T> {
+    present: AtomicBool,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+fn locate(index: usize) -> (usize, usize) {
+    let bucket = (usize::BITS - (index + 1).leading_zeros() - 1) as usize;
+    (bucket, index + 1 - (1 << bucket))
+}
+
+fn bucket_len(bucket: usize) -> usize {
+    1 << bucket
+}
+
+struct Inner<T> {
+    buckets: [AtomicPtr<Entry<T>>; BUCKETS],
+}
+
+impl<T> Inner<T> {
+    fn entry(&self, index: usize) -> Option<&Entry<T>> {
+        let (bucket, offset) = locate(index);
+        let entries = self.buckets[bucket].load(Ordering::Acquire);
+        if entries.is_null() {
+            None
+        } else {
+            Some(unsafe { &*entries.add(offset) })
+        }
+    }
+
+    fn entry_or_allocate(&self, index: usize) -> &Entry<T> {
+        let (bucket, offset) = locate(index);
+        let slot = &self.buckets[bucket];
+        let mut entries = slot.load(Ordering::Acquire);
+        if entries.is_null() {
+            let fresh = allocate_bucket::<T>(bucket_len(bucket));
+            entries = match slot.compare_exchange(
+                ptr::null_mut(),
+                fresh,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(..) => fresh,
+                Err(existing) => {
+                    unsafe { free_bucket(fresh, bucket_len(bucket)) };
+                    existing
+                }
+            };
+        }
+        unsafe { &*entries.add(offset) }
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &Entry<T>> {
+        self.buckets.iter().enumerate().flat_map(|(bucket, slot)| {
+            let entries = slot.load(Ordering::Acquire);
+            let len = if entries.is_null() {
+                0
+            } else {
+                bucket_len(bucket)
+            };
+            (0..len).map(move |offset| unsafe { &*entries.add(offset) })
+        })
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        for (bucket, slot) in self.buckets.iter_mut().enumerate() {
+            let entries = *slot.get_mut();
+            if entries.is_null() {
+                continue;
+            }
+            for offset in 0..bucket_len(bucket) {
+                let entry = unsafe { &mut *entries.add(offset) };
+                if *entry.present.get_mut() {
+                    unsafe { ptr::drop_in_place(entry.value.get_mut().as_mut_ptr()) };
+                }
+            }
+            unsafe { free_bucket(entries, bucket_len(bucket)) };
+        }
+    }
+}
+
+fn allocate_bucket<T>(len: usize) -> *mut Entry<T> {
+    let entries: Box<[Entry<T>]> = (0..len)
+        .map(|_| Entry {
+            present: AtomicBool::new(false),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        })
+        .collect();
+    Box::into_raw(entries) as *mut Entry<T>
+}
+
+unsafe fn free_bucket<T>(entries: *mut Entry<T>, len: usize) {
+    drop(Box::from_raw(ptr::slice_from_raw_parts_mut(entries, len)));
+}
+
+/// Thread-local storage that is owned by a value, rather than by a static.
+///
+/// A `ThreadLocal` holds one value per thread, created lazily on the first
+/// access from that thread. Values are looked up by `index()` without taking a
+/// lock, and iterating over the values of all threads, for example to
+/// aggregate per-thread results, does not take a lock either.
+///
+/// References returned by `get()` live as long as the `ThreadLocal`, so values
+/// are not dropped when their thread exits. Instead, the next thread that gets
+/// the same `index()` takes the value over, and `get()` returns it on that
+/// thread. This includes threads that get the index of a thread that does not
+/// exist in a forked child. Values are dropped with the `ThreadLocal`.
+pub struct ThreadLocal<T: Send + 'static> {
+    inner: Inner<T>,
+}
+
+// Only one live thread at a time has the index of a value, which is why `T`
+// only needs to be `Send`. Iterating over all values has an extra `T: Sync`
+// bound.
+unsafe impl<T: Send> Sync for ThreadLocal<T> {}
+unsafe impl<T: Send> Send for ThreadLocal<T> {}
+
+impl<T: Send + 'static> ThreadLocal<T> {
+    /// Creates a `ThreadLocal` without any values.
+    pub fn new() -> ThreadLocal<T> {
+        ThreadLocal {
+            inner: Inner {
+                buckets: [(); BUCKETS].map(|_| AtomicPtr::new(ptr::null_mut())),
+            },
+        }
+    }
+
+    /// Returns the value of the calling thread, if it has one.
+    ///
+    /// This may be the value of an exited thread that had the same `index()`.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        let entry = self.inner.entry(::index())?;
+        if entry.present.load(Ordering::Acquire) {
+            Some(unsafe { &*(*entry.value.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the value of the calling thread, creating it with `create` if
+    /// the thread does not have one yet.
+    ///
+    /// If `create` itself initializes the value of the calling thread, that
+    /// value is kept, and the value returned by `create` is dropped.
+    #[inline]
+    pub fn get_or<F: FnOnce() -> T>(&self, create: F) -> &T {
+        match self.get() {
+            Some(value) => value,
+            None => self.insert(create()),
+        }
+    }
+
+    /// Returns the value of the calling thread, creating a default value if
+    /// the thread does not have one yet.
+    #[inline]
+    pub fn get_or_default(&self) -> &T
+    where
+        T: Default,
+    {
+        self.get_or(T::default)
+    }
+
+    #[cold]
+    fn insert(&self, value: T) -> &T {
+        let entry = self.inner.entry_or_allocate(::index());
+        if entry.present.load(Ordering::Acquire) {
+            // The `create` closure of `get_or` inserted a value through a
+            // reentrant call, and that value may be borrowed already.
+            drop(value);
+        } else {
+            unsafe { (*entry.value.get()).as_mut_ptr().write(value) };
+            entry.present.store(true, Ordering::Release);
+        }
+        unsafe { &*(*entry.value.get()).as_ptr() }
+    }
+
+    /// Returns an iterator over the values of all threads, including values
+    /// of exited threads that no thread has taken over.
+    pub fn iter(&self) -> Iter<'_, T>
+    where
+        T: Sync,
+    {
+        Iter {
+            entries: Box::new(
+                self.inner
+                    .entries()
+                    .filter(|entry| entry.present.load(Ordering::Acquire))
+                    .map(|entry| unsafe { &*(*entry.value.get()).as_ptr() }),
+            ),
+        }
+    }
+
+    /// Returns an iterator over mutable references to the values of all
+    /// threads.
+    pub fn iter_mut(&mut self) -> IterMut<'_, T> {
+        IterMut {
+            entries: Box::new(
+                self.inner
+                    .entries()
+                    .filter(|entry| entry.present.load(Ordering::Acquire))
+                    .map(|entry| unsafe { &mut *(*entry.value.get()).as_mut_ptr() }),
+            ),
+        }
+    }
+}
+
+impl<T: Send + 'static> Default for ThreadLocal<T> {
+    fn default() -> ThreadLocal<T> {
+        ThreadLocal::new()
+    }
+}
+
+impl<T: Send + fmt::Debug + 'static> fmt::Debug for ThreadLocal<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ThreadLocal")
+            .field("local_data", &self.get())
+            .finish()
+    }
+}
+
+/// An iterator over the values of a `ThreadLocal`, returned by `iter()`.
+pub struct Iter<'a, T: 'a> {
+    entries: Box<dyn Iterator<Item = &'a T> + 'a>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.entries.next()
+    }
+}
+
+/// A mutable iterator over the values of a `ThreadLocal`, returned by
+/// `iter_mut()`.
+pub struct IterMut<'a, T: 'a> {
+    entries: Box<dyn Iterator<Item = &'a mut T> + 'a>,
+}
+
+impl<'a, T> Iterator for IterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        self.entries.next()
+    }
+}
+
+/// An owning iterator over the values of a `ThreadLocal`.
+pub struct IntoIter<T> {
+    values: vec::IntoIter<T>,
+}
+
+impl<T> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.values.next()
+    }
+}
+
+impl<T: Send + 'static> IntoIterator for ThreadLocal<T> {
+    type Item = T;
+    type IntoIter = IntoIter<T>;
+
+    fn into_iter(self) -> IntoIter<T> {
+        let values = self
+            .inner
+            .entries()
+            .filter(|entry| entry.present.swap(false, Ordering::AcqRel))
+            .map(|entry| unsafe { ptr::read((*entry.value.get()).as_ptr()) })
+            .collect::<Vec<T>>();
+        IntoIter {
+            values: values.into_iter(),
+        }
+    }
+}
+
+impl<'a, T: Send + Sync + 'static> IntoIterator for &'a ThreadLocal<T> {
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self) -> Iter<'a, T> {
+        self.iter()
+    }
+}
+
+impl<'a, T: Send + 'static> IntoIterator for &'a mut ThreadLocal<T> {
+    type Item = &'a mut T;
+    type IntoIter = IterMut<'a, T>;
+
+    fn into_iter(self) -> IterMut<'a, T> {
+        self.iter_mut()
+    }
+}
+
+#[test]
+fn thread_local_holds_one_value_per_thread() {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::thread;
+
+    let local = Arc::new(ThreadLocal::new());
+    assert_eq!(local.get(), None);
+    local.get_or(|| Cell::new(1)).set(2);
+    assert_eq!(local.get().map(Cell::get), Some(2));
+
+    let other = local.clone();
+    thread::spawn(move || {
+        assert_eq!(other.get().map(Cell::get), None);
+        other.get_or(|| Cell::new(10));
+    })
+    .join()
+    .unwrap();
+
+    // The value of the exited thread stays until the `ThreadLocal` is dropped.
+    let mut local = Arc::try_unwrap(local).unwrap();
+    let mut values: Vec<_> = local.iter_mut().map(|value| value.get()).collect();
+    values.sort();
+    assert_eq!(values, [2, 10]);
+    let mut values: Vec<_> = local.into_iter().map(Cell::into_inner).collect();
+    values.sort();
+    assert_eq!(values, [2, 10]);
+}
+
+#[test]
+fn thread_local_iterates_over_live_threads() {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Counted(usize);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    let local = Arc::new(ThreadLocal::new());
+    let barrier = Arc::new(Barrier::new(5));
+    let handles: Vec<_> = (0..4)
+        .map(|i| {
+            let (local, barrier) = (local.clone(), barrier.clone());
+            thread::spawn(move || {
+                local.get_or(|| Counted(i));
+                // Stay alive until the main thread has iterated.
+                barrier.wait();
+                barrier.wait();
+            })
+        })
+        .collect();
+
+    barrier.wait();
+    let mut values: Vec<_> = local.iter().map(|value| value.0).collect();
+    values.sort();
+    assert_eq!(values, [0, 1, 2, 3]);
+    barrier.wait();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(local.iter().count(), 4);
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 0);
+    drop(local);
+    assert_eq!(DROPPED.load(Ordering::SeqCst), 4);
+}
+
+#[test]
+fn reentrant_get_or_keeps_the_first_value() {
+    let local = ThreadLocal::new();
+    let mut inner = None;
+    let outer = local.get_or(|| {
+        inner = Some(local.get_or(|| vec![1, 2, 3]));
+        vec![9]
+    });
+    assert_eq!(outer, &[1, 2, 3]);
+    assert_eq!(inner.unwrap(), &[1, 2, 3]);
+}
+
+#[test]
+fn values_outlive_their_thread() {
+    use std::thread;
+
+    let local = ThreadLocal::new();
+    let value: &String = thread::scope(|scope| {
+        scope
+            .spawn(|| local.get_or(|| "scoped".to_string()))
+            .join()
+            .unwrap()
+    });
+    // The thread has exited, and a new thread may have its index already.
+    assert_eq!(value, "scoped");
+    assert_eq!(local.iter().collect::<Vec<_>>(), ["scoped"]);
+}
+
+#[test]
+fn locate_maps_indices_to_buckets() {
+    assert_eq!(locate(0), (0, 0));
+    assert_eq!(locate(1), (1, 0));
+    assert_eq!(locate(2), (1, 1));
+    assert_eq!(locate(3), (2, 0));
+    assert_eq!(locate(6), (2, 3));
+    assert_eq!(locate(7), (3, 0));
+}
@@ -0,0 +1,697 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Locks that detect lock order inversions.
+//!
+//! The `Mutex` and `RwLock` in this module wrap the ones in `std::sync`. In
+//! debug builds, they record which locks every thread holds, and build a global
+//! graph of the order in which locks were acquired. When a thread acquires a
+//! lock in the opposite order of an order seen before, possibly through other
+//! locks, that is a potential deadlock, and it is reported before the thread
+//! blocks, whether or not the deadlock would actually occur this time.
+//!
+//! A report is a `Violation`, which describes both threads, with backtraces of
+//! where they acquired the locks involved. By default the acquiring thread
+//! panics with the report; `set_violation_handler()` replaces that. Backtraces
+//! are captured with `Backtrace::capture`, so they are only present when
+//! enabled with `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+//!
+//! In release builds, the wrappers are plain `std::sync` locks.
+
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{self, Arc, LockResult, PoisonError, TryLockError, TryLockResult};
+
+use id::ThreadId;
+
+/// An identity for a lock, assigned when it is first acquired, so that locks
+/// can be created in a `const` context.
+struct LockId(AtomicU64);
+
+static NEXT_LOCK_ID: AtomicU64 = AtomicU64::new(1);
+
+impl LockId {
+    const fn new() -> LockId {
+        LockId(AtomicU64::new(0))
+    }
+
+    #[cfg_attr(not(debug_assertions), allow(dead_code))]
+    fn get(&self) -> u64 {
+        let id = self.0.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new = NEXT_LOCK_ID.fetch_add(1, Ordering::Relaxed);
+        match self
+            .0
+            .compare_exchange(0, new, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => new,
+            Err(existing) => existing,
+        }
+    }
+}
+
+impl Drop for LockId {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        tracking::forget(*self.0.get_mut());
+    }
+}
+
+/// One side of a lock order inversion: a thread that acquired a lock while it
+/// held another one.
+#[derive(Debug)]
+pub struct Witness {
+    thread: ThreadId,
+    thread_name: Option<String>,
+    held: Arc<Backtrace>,
+    acquired: Arc<Backtrace>,
+}
+
+impl Witness {
+    /// Returns the thread that acquired the locks.
+    #[inline]
+    pub fn thread(&self) -> ThreadId {
+        self.thread
+    }
+
+    /// Returns the name of the thread, if it has one.
+    #[inline]
+    pub fn thread_name(&self) -> Option<&str> {
+        self.thread_name.as_ref().map(|name| &name[..])
+    }
+
+    /// Returns where the thread acquired the lock it held.
+    #[inline]
+    pub fn held_backtrace(&self) -> &Backtrace {
+        &self.held
+    }
+
+    /// Returns where the thread acquired the second lock.
+    #[inline]
+    pub fn acquired_backtrace(&self) -> &Backtrace {
+        &self.acquired
+    }
+}
+
+impl fmt::Display for Witness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread {}", self.thread)?;
+        if let Some(name) = self.thread_name() {
+            write!(f, " ({})", name)?;
+        }
+        write!(
+            f,
+            "\nheld lock acquired at:\n{}\nsecond lock acquired at:\n{}",
+            self.held, self.acquired
+        )
+    }
+}
+
+/// A potential deadlock: two threads that acquired the same locks in opposite
+/// orders.
+#[derive(Debug)]
+pub struct Violation {
+    established: Witness,
+    inverted: Witness,
+}
+
+impl Violation {
+    /// Returns the acquisition that established the lock order first.
+    ///
+    /// If the order was established through a chain of locks, this is the
+    /// first link of the chain.
+    #[inline]
+    pub fn established(&self) -> &Witness {
+        &self.established
+    }
+
+    /// Returns the acquisition that inverted the lock order. This is the
+    /// thread that reports the violation.
+    #[inline]
+    pub fn inverted(&self) -> &Witness {
+        &self.inverted
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "potential deadlock: lock order inversion\n\
+             established by {}\n\
+             inverted by {}",
+            self.established, self.inverted
+        )
+    }
+}
+
+/// Replaces what happens when a lock order inversion is detected.
+///
+/// The handler runs on the thread that inverted the order, before it blocks on
+/// the lock. The default handler panics with the violation. In release builds
+/// the handler is never called.
+pub fn set_violation_handler<F: Fn(&Violation) + Send + Sync + 'static>(f: F) {
+    *tracking::HANDLER
+        .write()
+        .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(f));
+}
+
+#[cfg_attr(not(debug_assertions), allow(dead_code))]
+mod tracking {
+    use std::backtrace::Backtrace;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockWriteGuard};
+
+    use super::{Violation, Witness};
+    use registry;
+
+    pub type Handler = Arc<dyn Fn(&Violation) + Send + Sync>;
+
+    pub static HANDLER: RwLock<Option<Handler>> = RwLock::new(None);
+
+    /// The first acquisition that ordered one lock before another.
+    pub struct Edge {
+        thread: ::ThreadId,
+        // Recorded up front, as the thread may have exited by the time the
+        // edge is reported.
+        thread_name: Option<String>,
+        held: Arc<Backtrace>,
+        acquired: Arc<Backtrace>,
+    }
+
+    /// For every lock, the locks that were acquired while it was held.
+    pub type Graph = HashMap<u64, HashMap<u64, Edge>>;
+
+    static GRAPH: Mutex<Option<Graph>> = Mutex::new(None);
+
+    struct Acquisition {
+        lock: u64,
+        backtrace: Arc<Backtrace>,
+    }
+
+    thread_local! {
+        static HELD_LOCKS: RefCell<Vec<Acquisition>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn graph() -> MutexGuard<'static, Option<Graph>> {
+        GRAPH.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the first edge of a path from `from` to `to`, if there is one.
+    fn find_path(graph: &Graph, from: u64, to: u64) -> Option<&Edge> {
+        let mut visited = HashSet::new();
+        let mut stack: Vec<(u64, &Edge)> = graph
+            .get(&from)?
+            .iter()
+            .map(|(&next, edge)| (next, edge))
+            .collect();
+        while let Some((lock, first)) = stack.pop() {
+            if lock == to {
+                return Some(first);
+            }
+            if visited.insert(lock) {
+                if let Some(edges) = graph.get(&lock) {
+                    stack.extend(edges.keys().map(|&next| (next, first)));
+                }
+            }
+        }
+        None
+    }
+
+    /// Checks the order of `lock` against the locks the thread holds, before
+    /// the thread blocks on it.
+    pub fn before_lock(lock: u64) {
+        let backtrace = Arc::new(Backtrace::capture());
+        // Registering the thread may run first-seen hooks, which may take
+        // locks themselves, so it must happen before `HELD_LOCKS` is borrowed
+        // and before the graph lock is taken.
+        let current = ::current();
+        let current_name = registry::current_name();
+        let violation = HELD_LOCKS
+            .try_with(|held| {
+                let thread = (current, current_name.as_deref());
+                check_order(&held.borrow(), lock, thread, &backtrace)
+            })
+            .unwrap_or(None);
+        if let Some(violation) = violation {
+            let handler = HANDLER
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            match handler {
+                Some(handler) => handler(&violation),
+                None => panic!("{}", violation),
+            }
+        }
+        after_lock(lock, backtrace);
+    }
+
+    fn check_order(
+        held: &[Acquisition],
+        lock: u64,
+        (current, current_name): (::ThreadId, Option<&str>),
+        backtrace: &Arc<Backtrace>,
+    ) -> Option<Violation> {
+        if held.is_empty() {
+            return None;
+        }
+        let mut graph = graph();
+        let graph = graph.get_or_insert_with(HashMap::new);
+        for acquisition in held.iter().filter(|a| a.lock != lock) {
+            if let Some(edge) = find_path(graph, lock, acquisition.lock) {
+                return Some(Violation {
+                    established: Witness {
+                        thread: edge.thread,
+                        thread_name: edge.thread_name.clone(),
+                        held: edge.held.clone(),
+                        acquired: edge.acquired.clone(),
+                    },
+                    inverted: Witness {
+                        thread: current,
+                        thread_name: current_name.map(String::from),
+                        held: acquisition.backtrace.clone(),
+                        acquired: backtrace.clone(),
+                    },
+                });
+            }
+        }
+        for acquisition in held.iter().filter(|a| a.lock != lock) {
+            graph
+                .entry(acquisition.lock)
+                .or_default()
+                .entry(lock)
+                .or_insert_with(|| Edge {
+                    thread: current,
+                    thread_name: current_name.map(String::from),
+                    held: acquisition.backtrace.clone(),
+                    acquired: backtrace.clone(),
+                });
+        }
+        None
+    }
+
+    /// Records that the thread holds `lock`.
+    pub fn after_lock(lock: u64, backtrace: Arc<Backtrace>) {
+        let _ = HELD_LOCKS.try_with(|held| held.borrow_mut().push(Acquisition { lock, backtrace }));
+    }
+
+    /// Records that the thread released `lock`.
+    pub fn unlock(lock: u64) {
+        let _ = HELD_LOCKS.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(i) = held.iter().rposition(|a| a.lock == lock) {
+                held.remove(i);
+            }
+        });
+    }
+
+    /// Removes a destroyed lock from the graph.
+    pub fn forget(lock: u64) {
+        if lock == 0 {
+            return;
+        }
+        if let Some(graph) = graph().as_mut() {
+            graph.remove(&lock);
+            for edges in graph.values_mut() {
+                edges.remove(&lock);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn lock_for_fork() -> MutexGuard<'static, Option<Graph>> {
+        graph()
+    }
+
+    #[cfg(unix)]
+    pub fn lock_handler_for_fork() -> RwLockWriteGuard<'static, Option<Handler>> {
+        HANDLER.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(all(unix, debug_assertions))]
+pub(crate) use self::tracking::{lock_for_fork, Graph};
+// The handler can be replaced in release builds too.
+#[cfg(unix)]
+pub(crate) use self::tracking::{lock_handler_for_fork, Handler};
+
+/// Marks a lock as held by the thread, and as released when dropped.
+struct Held {
+    #[cfg(debug_assertions)]
+    lock: u64,
+}
+
+impl Held {
+    #[inline]
+    fn before_lock(_id: &LockId) -> Held {
+        #[cfg(debug_assertions)]
+        {
+            let lock = _id.get();
+            tracking::before_lock(lock);
+            Held { lock }
+        }
+        #[cfg(not(debug_assertions))]
+        Held {}
+    }
+
+    /// For a lock acquired without blocking, which cannot deadlock.
+    #[inline]
+    fn after_try_lock(_id: &LockId) -> Held {
+        #[cfg(debug_assertions)]
+        {
+            let lock = _id.get();
+            tracking::after_lock(lock, Arc::new(Backtrace::capture()));
+            Held { lock }
+        }
+        #[cfg(not(debug_assertions))]
+        Held {}
+    }
+}
+
+impl Drop for Held {
+    #[inline]
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        tracking::unlock(self.lock);
+    }
+}
+
+fn map_lock<G, H, F: FnOnce(G) -> H>(result: LockResult<G>, f: F) -> LockResult<H> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(poisoned) => Err(PoisonError::new(f(poisoned.into_inner()))),
+    }
+}
+
+fn map_try_lock<G, H, F: FnOnce(G) -> H>(result: TryLockResult<G>, f: F) -> TryLockResult<H> {
+    match result {
+        Ok(guard) => Ok(f(guard)),
+        Err(TryLockError::Poisoned(poisoned)) => Err(TryLockError::Poisoned(PoisonError::new(f(
+            poisoned.into_inner(),
+        )))),
+        Err(TryLockError::WouldBlock) => Err(TryLockError::WouldBlock),
+    }
+}
+
+/// A `std::sync::Mutex` that detects lock order inversions in debug builds.
+pub struct Mutex<T: ?Sized> {
+    id: LockId,
+    inner: sync::Mutex<T>,
+}
+
+/// A guard for a `Mutex`.
+#[must_use = "if unused the Mutex will immediately unlock"]
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    // Unlock before marking the lock as released.
+    inner: sync::MutexGuard<'a, T>,
+    _held: Held,
+}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex.
+    pub const fn new(value: T) -> Mutex<T> {
+        Mutex {
+            id: LockId::new(),
+            inner: sync::Mutex::new(value),
+        }
+    }
+
+    /// Consumes the mutex and returns the value.
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquires the mutex, blocking until it is available.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, with the default violation handler, panics if this
+    /// inverts a previously seen lock order.
+    pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
+        let held = Held::before_lock(&self.id);
+        map_lock(self.inner.lock(), |inner| MutexGuard { inner, _held: held })
+    }
+
+    /// Acquires the mutex if it is available.
+    ///
+    /// This cannot deadlock, so it does not check the lock order.
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        map_try_lock(self.inner.try_lock(), |inner| MutexGuard {
+            inner,
+            _held: Held::after_try_lock(&self.id),
+        })
+    }
+
+    /// Returns a mutable reference to the value, which needs no locking.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for MutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+/// A `std::sync::RwLock` that detects lock order inversions in debug builds.
+///
+/// Read and write acquisitions are treated the same, because a reader can
+/// block on a writer that waits for a lock the reader holds.
+pub struct RwLock<T: ?Sized> {
+    id: LockId,
+    inner: sync::RwLock<T>,
+}
+
+/// A guard for shared access to an `RwLock`.
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    inner: sync::RwLockReadGuard<'a, T>,
+    _held: Held,
+}
+
+/// A guard for exclusive access to an `RwLock`.
+#[must_use = "if unused the RwLock will immediately unlock"]
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    inner: sync::RwLockWriteGuard<'a, T>,
+    _held: Held,
+}
+
+impl<T> RwLock<T> {
+    /// Creates an unlocked lock.
+    pub const fn new(value: T) -> RwLock<T> {
+        RwLock {
+            id: LockId::new(),
+            inner: sync::RwLock::new(value),
+        }
+    }
+
+    /// Consumes the lock and returns the value.
+    pub fn into_inner(self) -> LockResult<T> {
+        self.inner.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Acquires shared access, blocking until it is available.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, with the default violation handler, panics if this
+    /// inverts a previously seen lock order.
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let held = Held::before_lock(&self.id);
+        map_lock(self.inner.read(), |inner| RwLockReadGuard {
+            inner,
+            _held: held,
+        })
+    }
+
+    /// Acquires exclusive access, blocking until it is available.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, with the default violation handler, panics if this
+    /// inverts a previously seen lock order.
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let held = Held::before_lock(&self.id);
+        map_lock(self.inner.write(), |inner| RwLockWriteGuard {
+            inner,
+            _held: held,
+        })
+    }
+
+    /// Acquires shared access if it is available.
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+        map_try_lock(self.inner.try_read(), |inner| RwLockReadGuard {
+            inner,
+            _held: Held::after_try_lock(&self.id),
+        })
+    }
+
+    /// Acquires exclusive access if it is available.
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+        map_try_lock(self.inner.try_write(), |inner| RwLockWriteGuard {
+            inner,
+            _held: Held::after_try_lock(&self.id),
+        })
+    }
+
+    /// Returns a mutable reference to the value, which needs no locking.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        self.inner.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T: ?Sized> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<'a, T: ?Sized> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockReadGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for RwLockWriteGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn lock_order_inversion_is_reported_without_deadlocking() {
+    use std::thread;
+
+    let a = Arc::new(Mutex::new(()));
+    let b = Arc::new(RwLock::new(()));
+    let c = Mutex::new(());
+
+    // Establish a -> b -> c on one thread.
+    let (a2, b2) = (a.clone(), b.clone());
+    let first = thread::Builder::new()
+        .name("first".to_string())
+        .spawn(move || {
+            let _a = a2.lock().unwrap();
+            let _b = b2.read().unwrap();
+            ::current()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    {
+        let _b = b.write().unwrap();
+        let _c = c.lock().unwrap();
+    }
+
+    // Taking a while holding b inverts the order. The first thread is long
+    // gone, so this would never deadlock, but it could have.
+    let message = thread::Builder::new()
+        .name("second".to_string())
+        .spawn(move || {
+            let _b = b.read().unwrap();
+            let _a = a.lock().unwrap();
+        })
+        .unwrap()
+        .join()
+        .unwrap_err();
+    let message = message.downcast_ref::<String>().unwrap();
+    assert!(message.starts_with("potential deadlock"));
+    assert!(message.contains(&format!("established by thread {} (first)", first)));
+    assert!(message.contains("(second)"));
+
+    // Consistent orders and try_lock are fine.
+    let x = Mutex::new(());
+    let y = Mutex::new(());
+    {
+        let _x = x.lock().unwrap();
+        let _y = y.lock().unwrap();
+    }
+    let _y = y.lock().unwrap();
+    let _x = x.try_lock().unwrap();
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn first_seen_hooks_can_take_locks() {
+    use std::thread;
+
+    static HOOK_LOCK: Mutex<u32> = Mutex::new(0);
+
+    // The hook applies to every thread from now on, which is harmless.
+    ::on_thread_first_seen(|_| *HOOK_LOCK.lock().unwrap() += 1);
+    let lock = Mutex::new(());
+    thread::scope(|scope| {
+        // The first lock registers the thread, which runs the hook.
+        scope.spawn(|| drop(lock.lock().unwrap()));
+    });
+    assert!(*HOOK_LOCK.lock().unwrap() > 0);
+}
@@ -0,0 +1,214 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! A `log` logger that prefixes records with the identity of the thread.
+
+use std::fmt;
+use std::mem;
+
+use log::{Log, Metadata, Record};
+
+use id::ThreadId;
+use registry;
+
+/// The prefix format used by `ThreadLogger::new`.
+pub const DEFAULT_LOG_PREFIX: &str = "[{name} {id}/{os_tid}] ";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Piece {
+    Literal(String),
+    Id,
+    OsTid,
+    Name,
+}
+
+/// A logger that forwards records to an inner logger, with the message
+/// prefixed by the identity of the logging thread.
+///
+/// The prefix is a template with these placeholders:
+///
+///  * `{id}`: the `ThreadId` of the thread.
+///  * `{os_tid}`: the kernel thread ID, or `-` where it is not available.
+///  * `{name}`: the name of the thread in the registry, or `-` if it has none.
+///
+/// Other text, including braces that do not form a placeholder, is copied
+/// verbatim.
+///
+/// ```ignore
+/// let logger = ThreadLogger::new(env_logger::Logger::from_default_env());
+/// log::set_max_level(logger.inner().filter());
+/// log::set_boxed_logger(Box::new(logger)).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct ThreadLogger<L> {
+    inner: L,
+    prefix: Vec<Piece>,
+}
+
+impl<L: Log> ThreadLogger<L> {
+    /// Wraps `inner`, with the prefix format `DEFAULT_LOG_PREFIX`.
+    pub fn new(inner: L) -> ThreadLogger<L> {
+        ThreadLogger::with_prefix(inner, DEFAULT_LOG_PREFIX)
+    }
+
+    /// Wraps `inner`, with the given prefix format.
+    pub fn with_prefix(inner: L, format: &str) -> ThreadLogger<L> {
+        ThreadLogger {
+            inner,
+            prefix: parse_prefix(format),
+        }
+    }
+
+    /// Returns the inner logger.
+    #[inline]
+    pub fn inner(&self) -> &L {
+        &self.inner
+    }
+}
+
+fn parse_prefix(mut format: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    while !format.is_empty() {
+        let placeholder = [
+            ("{id}", Piece::Id),
+            ("{os_tid}", Piece::OsTid),
+            ("{name}", Piece::Name),
+        ]
+        .iter()
+        .find(|(pattern, _)| format.starts_with(pattern))
+        .cloned();
+        match placeholder {
+            Some((pattern, piece)) => {
+                if !literal.is_empty() {
+                    pieces.push(Piece::Literal(mem::take(&mut literal)));
+                }
+                pieces.push(piece);
+                format = &format[pattern.len()..];
+            }
+            None => {
+                let c = format.chars().next().unwrap();
+                literal.push(c);
+                format = &format[c.len_utf8()..];
+            }
+        }
+    }
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    pieces
+}
+
+/// The prefix for one record.
+struct Prefix<'a> {
+    pieces: &'a [Piece],
+    id: ThreadId,
+    os_tid: Option<u32>,
+    name: Option<&'a str>,
+}
+
+impl<'a> fmt::Display for Prefix<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for piece in self.pieces {
+            match *piece {
+                Piece::Literal(ref text) => f.write_str(text)?,
+                Piece::Id => write!(f, "{}", self.id)?,
+                Piece::OsTid => match self.os_tid {
+                    Some(tid) => write!(f, "{}", tid)?,
+                    None => f.write_str("-")?,
+                },
+                Piece::Name => f.write_str(self.name.unwrap_or("-"))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<L: Log> Log for ThreadLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        // Read from per-thread caches; this runs for every record.
+        let id = ::current();
+        let name = registry::current_name();
+        let prefix = Prefix {
+            pieces: &self.prefix,
+            id,
+            os_tid: registry::current_os_tid(),
+            name: name.as_deref(),
+        };
+        self.inner.log(
+            &Record::builder()
+                .metadata(record.metadata().clone())
+                .args(format_args!("{}{}", prefix, record.args()))
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn thread_logger_prefixes_records() {
+    use std::sync::Mutex;
+    use std::thread;
+
+    use log::Level;
+
+    struct Capture(Mutex<Vec<String>>);
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Info
+        }
+        fn log(&self, record: &Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+        fn flush(&self) {}
+    }
+
+    let logger = ThreadLogger::with_prefix(Capture(Mutex::new(Vec::new())), "{id} {name}: {x} ");
+    let (id, records) = thread::Builder::new()
+        .name("logging".to_string())
+        .spawn(move || {
+            for &level in &[Level::Info, Level::Debug] {
+                logger.log(
+                    &Record::builder()
+                        .level(level)
+                        .args(format_args!("hello"))
+                        .build(),
+                );
+            }
+            (::current(), logger.inner.0.into_inner().unwrap())
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(records, [format!("{} logging: {{x}} hello", id)]);
+
+    let default = parse_prefix(DEFAULT_LOG_PREFIX);
+    assert_eq!(
+        default[1..6],
+        [
+            Piece::Name,
+            Piece::Literal(" ".to_string()),
+            Piece::Id,
+            Piece::Literal("/".to_string()),
+            Piece::OsTid,
+        ]
+    );
+}
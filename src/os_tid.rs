@@ -0,0 +1,64 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! The kernel thread ID on Linux.
+
+use std::cell::Cell;
+
+use fork;
+
+thread_local! {
+    // Zero means the TID has not been queried yet; the kernel never uses it.
+    static OS_TID: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Returns the kernel thread ID of the calling thread, as returned by `gettid`.
+///
+/// This is the ID that `ps -L`, `top -H`, `perf`, `gdb` and `/proc/self/task`
+/// show, unlike `get()`, which returns the `pthread_self` handle. The ID is
+/// cached per thread after the first call, so subsequent calls do not make a
+/// system call.
+#[inline]
+pub fn os_tid() -> u32 {
+    OS_TID
+        .try_with(|cached| {
+            let tid = cached.get();
+            if tid != 0 {
+                return tid;
+            }
+            fork::register();
+            let tid = gettid();
+            cached.set(tid);
+            tid
+        })
+        // During thread teardown the cache may be gone; ask the kernel.
+        .unwrap_or_else(|_| gettid())
+}
+
+/// Clears the cache, because the thread has a new TID in a forked child.
+pub(crate) fn after_fork_in_child() {
+    let _ = OS_TID.try_with(|cached| cached.set(0));
+}
+
+fn gettid() -> u32 {
+    unsafe { libc::syscall(libc::SYS_gettid) as u32 }
+}
+
+#[test]
+fn os_tid_is_listed_in_proc() {
+    let tid = os_tid();
+    assert_eq!(tid, os_tid());
+    assert!(::std::path::Path::new(&format!("/proc/self/task/{}", tid)).exists());
+}
+
+#[test]
+fn distinct_threads_have_distinct_os_tids() {
+    use std::thread;
+
+    let other = thread::spawn(os_tid).join().unwrap();
+    assert!(os_tid() != other);
+}
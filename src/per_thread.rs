@@ -0,0 +1,322 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Sharded per-thread slots for contention-free statistics.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::hint;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+/// A slot on its own cache line, so that threads updating adjacent slots do not
+/// invalidate each other's caches. 128 bytes covers adjacent-line prefetching.
+#[repr(align(128))]
+struct Slot<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+struct SlotGuard<'a, T: 'a> {
+    slot: &'a Slot<T>,
+}
+
+impl<T> Slot<T> {
+    fn lock(&self) -> SlotGuard<'_, T> {
+        // The slot is normally only used by one thread, so the lock is
+        // uncontended, apart from during a fold.
+        while self.locked.swap(true, Ordering::Acquire) {
+            while self.locked.load(Ordering::Relaxed) {
+                hint::spin_loop();
+            }
+        }
+        SlotGuard { slot: self }
+    }
+}
+
+impl<'a, T> Drop for SlotGuard<'a, T> {
+    fn drop(&mut self) {
+        self.slot.locked.store(false, Ordering::Release);
+    }
+}
+
+/// A fixed number of cache-line padded slots, one per thread.
+///
+/// Every thread uses the slot at its `index()`, modulo the capacity, so as long
+/// as there are no more live threads than slots, threads do not share slots.
+/// Slots are still guarded by a lock, so sharing is correct, only slower. This
+/// makes `PerThread` suitable for statistics that are updated often and read
+/// rarely, such as counters: updates touch only the slot of the thread, and
+/// reads fold over all slots.
+pub struct PerThread<T> {
+    slots: Box<[Slot<T>]>,
+}
+
+unsafe impl<T: Send> Send for PerThread<T> {}
+unsafe impl<T: Send> Sync for PerThread<T> {}
+
+impl<T> PerThread<T> {
+    /// Creates `capacity` slots holding the default value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> PerThread<T>
+    where
+        T: Default,
+    {
+        PerThread::with_init(capacity, T::default)
+    }
+
+    /// Creates `capacity` slots, holding values created by `init`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_init<F: FnMut() -> T>(capacity: usize, mut init: F) -> PerThread<T> {
+        assert!(capacity > 0, "PerThread needs at least one slot");
+        PerThread {
+            slots: (0..capacity)
+                .map(|_| Slot {
+                    locked: AtomicBool::new(false),
+                    value: UnsafeCell::new(init()),
+                })
+                .collect(),
+        }
+    }
+
+    /// Returns the number of slots.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Calls `f` with the slot of the calling thread.
+    ///
+    /// Calling `with_mut` or `fold` on the same `PerThread` from within `f`
+    /// deadlocks.
+    #[inline]
+    pub fn with_mut<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let slot = &self.slots[::index() % self.slots.len()];
+        let _guard = slot.lock();
+        f(unsafe { &mut *slot.value.get() })
+    }
+
+    /// Folds `f` over the values of all slots, in slot order.
+    ///
+    /// Slots are locked one at a time, so the result is not a consistent
+    /// snapshot when other threads update their slots concurrently.
+    pub fn fold<B, F: FnMut(B, &T) -> B>(&self, init: B, mut f: F) -> B {
+        let mut acc = init;
+        for slot in self.slots.iter() {
+            let _guard = slot.lock();
+            acc = f(acc, unsafe { &*slot.value.get() });
+        }
+        acc
+    }
+
+    /// Returns mutable references to the values of all slots.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().map(|slot| slot.value.get_mut())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for PerThread<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        for slot in self.slots.iter() {
+            let _guard = slot.lock();
+            list.entry(unsafe { &*slot.value.get() });
+        }
+        list.finish()
+    }
+}
+
+/// The number of slots for sharded types that do not specify a capacity.
+fn default_capacity() -> usize {
+    thread::available_parallelism().map_or(8, |n| n.get() * 2)
+}
+
+/// A counter that threads can increment without contending on a cache line.
+#[derive(Debug)]
+pub struct ShardedCounter {
+    shards: PerThread<u64>,
+}
+
+impl ShardedCounter {
+    /// Creates a counter at zero, with twice as many shards as the machine has
+    /// hardware threads.
+    pub fn new() -> ShardedCounter {
+        ShardedCounter::with_capacity(default_capacity())
+    }
+
+    /// Creates a counter at zero, with the given number of shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_capacity(capacity: usize) -> ShardedCounter {
+        ShardedCounter {
+            shards: PerThread::new(capacity),
+        }
+    }
+
+    /// Adds `n` to the counter, wrapping around on overflow.
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.shards.with_mut(|count| *count = count.wrapping_add(n));
+    }
+
+    /// Adds one to the counter.
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    /// Returns the sum of all shards.
+    pub fn sum(&self) -> u64 {
+        self.shards.fold(0, |sum, &count| sum.wrapping_add(count))
+    }
+}
+
+impl Default for ShardedCounter {
+    fn default() -> ShardedCounter {
+        ShardedCounter::new()
+    }
+}
+
+#[derive(Debug)]
+struct HistogramShard {
+    counts: Box<[u64]>,
+    sum: u64,
+}
+
+/// A histogram that threads can record into without contending on a cache
+/// line.
+///
+/// Values are counted in buckets with fixed upper bounds. Bucket `i` counts the
+/// values that are at most `bounds[i]` and greater than the previous bound, and
+/// one extra bucket counts the values greater than the last bound.
+#[derive(Debug)]
+pub struct ShardedHistogram {
+    bounds: Box<[u64]>,
+    shards: PerThread<HistogramShard>,
+}
+
+impl ShardedHistogram {
+    /// Creates an empty histogram with the given bucket upper bounds.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bounds are not strictly increasing.
+    pub fn new(bounds: &[u64]) -> ShardedHistogram {
+        ShardedHistogram::with_capacity(bounds, default_capacity())
+    }
+
+    /// Creates an empty histogram with the given bucket upper bounds and the
+    /// given number of shards.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the bounds are not strictly increasing, or if `capacity` is
+    /// zero.
+    pub fn with_capacity(bounds: &[u64], capacity: usize) -> ShardedHistogram {
+        assert!(
+            bounds.windows(2).all(|pair| pair[0] < pair[1]),
+            "histogram bounds must be strictly increasing"
+        );
+        let buckets = bounds.len() + 1;
+        ShardedHistogram {
+            bounds: bounds.into(),
+            shards: PerThread::with_init(capacity, || HistogramShard {
+                counts: vec![0; buckets].into_boxed_slice(),
+                sum: 0,
+            }),
+        }
+    }
+
+    /// Returns the upper bounds of the buckets.
+    #[inline]
+    pub fn bounds(&self) -> &[u64] {
+        &self.bounds
+    }
+
+    /// Records a value.
+    #[inline]
+    pub fn record(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.shards.with_mut(|shard| {
+            shard.counts[bucket] += 1;
+            shard.sum = shard.sum.wrapping_add(value);
+        });
+    }
+
+    /// Returns the number of values in every bucket, including the last one
+    /// for values greater than all bounds.
+    pub fn counts(&self) -> Vec<u64> {
+        let buckets = vec![0; self.bounds.len() + 1];
+        self.shards.fold(buckets, |mut buckets, shard| {
+            for (total, count) in buckets.iter_mut().zip(shard.counts.iter()) {
+                *total += count;
+            }
+            buckets
+        })
+    }
+
+    /// Returns the number of values recorded.
+    pub fn count(&self) -> u64 {
+        self.counts().iter().sum()
+    }
+
+    /// Returns the sum of the values recorded, wrapping around on overflow.
+    pub fn sum(&self) -> u64 {
+        self.shards
+            .fold(0, |sum, shard| sum.wrapping_add(shard.sum))
+    }
+}
+
+#[test]
+fn sharded_counter_sums_over_threads() {
+    use std::sync::Arc;
+
+    let counter = Arc::new(ShardedCounter::with_capacity(2));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    counter.inc();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    counter.add(5);
+    assert_eq!(counter.sum(), 4005);
+}
+
+#[test]
+fn sharded_histogram_counts_buckets() {
+    let histogram = ShardedHistogram::new(&[10, 100]);
+    for &value in &[0, 10, 11, 100, 101, 5000] {
+        histogram.record(value);
+    }
+    assert_eq!(histogram.counts(), [2, 2, 2]);
+    assert_eq!(histogram.count(), 6);
+    assert_eq!(histogram.sum(), 5222);
+}
+
+#[test]
+fn per_thread_slot_is_stable_per_thread() {
+    let mut slots = PerThread::<Vec<usize>>::new(4);
+    slots.with_mut(|slot| slot.push(1));
+    slots.with_mut(|slot| slot.push(2));
+    assert_eq!(slots.fold(0, |n, slot| n + slot.len()), 2);
+    assert!(slots.iter_mut().any(|slot| *slot == [1, 2]));
+}
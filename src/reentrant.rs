@@ -0,0 +1,314 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! A reentrant mutex keyed by thread ID.
+
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::Ordering;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use atomic::AtomicThreadId;
+use id::ThreadId;
+
+/// A mutex that the thread holding it can lock again.
+///
+/// Other threads block until the owning thread has released every lock it
+/// took. Because the owning thread can hold several guards at once, a guard
+/// only gives shared access to the data; use a `Cell` or `RefCell` inside the
+/// mutex for mutation.
+pub struct ReentrantMutex<T: ?Sized> {
+    owner: AtomicThreadId,
+    /// The number of guards held by the owner. Only the owner accesses it.
+    depth: UnsafeCell<usize>,
+    locked: Mutex<bool>,
+    unlocked: Condvar,
+    data: T,
+}
+
+unsafe impl<T: Send + ?Sized> Send for ReentrantMutex<T> {}
+unsafe impl<T: Send + ?Sized> Sync for ReentrantMutex<T> {}
+
+/// A guard that releases one level of a `ReentrantMutex` when dropped.
+#[must_use = "if unused the ReentrantMutex will immediately unlock"]
+pub struct ReentrantMutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a ReentrantMutex<T>,
+    // The guard must be dropped on the thread that owns the mutex.
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<T> ReentrantMutex<T> {
+    /// Creates an unlocked mutex holding `data`.
+    pub const fn new(data: T) -> ReentrantMutex<T> {
+        ReentrantMutex {
+            owner: AtomicThreadId::none(),
+            depth: UnsafeCell::new(0),
+            locked: Mutex::new(false),
+            unlocked: Condvar::new(),
+            data,
+        }
+    }
+
+    /// Consumes the mutex and returns the data.
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+impl<T: ?Sized> ReentrantMutex<T> {
+    fn locked(&self) -> MutexGuard<'_, bool> {
+        self.locked.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the mutex, blocking until it is available.
+    ///
+    /// If the calling thread holds the mutex already, this returns right away
+    /// and increments the lock depth.
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        if let Some(guard) = self.lock_again() {
+            return guard;
+        }
+        let mut locked = self.locked();
+        while *locked {
+            locked = self
+                .unlocked
+                .wait(locked)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        self.acquire(locked)
+    }
+
+    /// Locks the mutex if it is available or held by the calling thread, and
+    /// returns `None` otherwise.
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        if let Some(guard) = self.lock_again() {
+            return Some(guard);
+        }
+        let locked = self.locked();
+        if *locked {
+            None
+        } else {
+            Some(self.acquire(locked))
+        }
+    }
+
+    /// Locks the mutex, blocking for at most `timeout` until it is available.
+    ///
+    /// Returns `None` if the mutex is still held by a different thread after
+    /// the timeout.
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<ReentrantMutexGuard<'_, T>> {
+        if let Some(guard) = self.lock_again() {
+            return Some(guard);
+        }
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            // The deadline is too far away to represent, so it never passes.
+            None => return Some(self.lock()),
+        };
+        let mut locked = self.locked();
+        while *locked {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            locked = self
+                .unlocked
+                .wait_timeout(locked, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        Some(self.acquire(locked))
+    }
+
+    /// Returns the thread that holds the mutex, if it is locked.
+    #[inline]
+    pub fn owner(&self) -> Option<ThreadId> {
+        self.owner.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of guards that the calling thread holds.
+    ///
+    /// This is zero if the mutex is unlocked or held by a different thread.
+    #[inline]
+    pub fn lock_depth(&self) -> usize {
+        if self.owner.is_current() {
+            unsafe { *self.depth.get() }
+        } else {
+            0
+        }
+    }
+
+    /// Returns a mutable reference to the data, which needs no locking.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+
+    /// Increments the depth if the calling thread holds the mutex.
+    #[inline]
+    fn lock_again(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        // Only the calling thread can store its own ID, so if it reads its own
+        // ID, it holds the mutex, and no other thread touches the depth.
+        if !self.owner.is_current() {
+            return None;
+        }
+        unsafe {
+            let depth = &mut *self.depth.get();
+            *depth = depth.checked_add(1).expect("lock depth overflow");
+        }
+        Some(self.guard())
+    }
+
+    fn acquire(&self, mut locked: MutexGuard<'_, bool>) -> ReentrantMutexGuard<'_, T> {
+        *locked = true;
+        drop(locked);
+        self.owner.store(Some(::current()), Ordering::Relaxed);
+        unsafe { *self.depth.get() = 1 };
+        self.guard()
+    }
+
+    fn guard(&self) -> ReentrantMutexGuard<'_, T> {
+        ReentrantMutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: Default> Default for ReentrantMutex<T> {
+    fn default() -> ReentrantMutex<T> {
+        ReentrantMutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ReentrantMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("ReentrantMutex");
+        match self.try_lock() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("owner", &self.owner()).finish()
+    }
+}
+
+impl<'a, T: ?Sized> Deref for ReentrantMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.mutex.data
+    }
+}
+
+impl<'a, T: ?Sized> Drop for ReentrantMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let mutex = self.mutex;
+        let depth = unsafe { &mut *mutex.depth.get() };
+        *depth -= 1;
+        if *depth == 0 {
+            mutex.owner.store(None, Ordering::Relaxed);
+            *mutex.locked() = false;
+            mutex.unlocked.notify_one();
+        }
+    }
+}
+
+impl<'a, T: ?Sized + fmt::Debug> fmt::Debug for ReentrantMutexGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[test]
+fn reentrant_mutex_can_be_locked_recursively() {
+    use std::cell::RefCell;
+
+    let mutex = ReentrantMutex::new(RefCell::new(Vec::new()));
+    assert_eq!(mutex.owner(), None);
+    assert_eq!(mutex.lock_depth(), 0);
+    {
+        let outer = mutex.lock();
+        outer.borrow_mut().push(1);
+        let inner = mutex.try_lock().unwrap();
+        inner.borrow_mut().push(2);
+        assert_eq!(mutex.lock_depth(), 2);
+        assert_eq!(mutex.owner(), Some(::current()));
+    }
+    assert_eq!(mutex.owner(), None);
+    assert_eq!(mutex.into_inner().into_inner(), [1, 2]);
+}
+
+#[test]
+fn reentrant_mutex_blocks_other_threads() {
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    let mutex = Arc::new(ReentrantMutex::new(()));
+    let guard = mutex.lock();
+
+    let other = mutex.clone();
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        assert!(other.try_lock().is_none());
+        assert!(other.try_lock_for(Duration::from_millis(10)).is_none());
+        assert_eq!(other.lock_depth(), 0);
+        tx.send(()).unwrap();
+        // A timeout too long to represent as a deadline blocks like `lock()`.
+        let _guard = other.try_lock_for(Duration::MAX).unwrap();
+        assert_eq!(other.owner(), Some(::current()));
+    });
+
+    rx.recv().unwrap();
+    drop(guard);
+    handle.join().unwrap();
+    assert_eq!(mutex.owner(), None);
+}
+
+#[test]
+fn reentrant_mutex_hands_off_ownership_under_stress() {
+    use std::cell::Cell;
+    use std::sync::Arc;
+    use std::thread;
+
+    const THREADS: usize = 8;
+    const ITERATIONS: usize = 2000;
+
+    // The counter is not atomic, so lost updates would show up as a wrong
+    // total if two threads held the mutex at once.
+    let mutex = Arc::new(ReentrantMutex::new(Cell::new(0)));
+    let handles: Vec<_> = (0..THREADS)
+        .map(|_| {
+            let mutex = mutex.clone();
+            thread::spawn(move || {
+                let me = ::current();
+                for i in 0..ITERATIONS {
+                    let outer = mutex.lock();
+                    assert_eq!(mutex.owner(), Some(me));
+                    let inner = if i % 2 == 0 {
+                        mutex.lock()
+                    } else {
+                        mutex.try_lock_for(Duration::from_secs(0)).unwrap()
+                    };
+                    assert_eq!(mutex.lock_depth(), 2);
+                    let count = inner.get();
+                    thread::yield_now();
+                    outer.set(count + 1);
+                    drop(inner);
+                    assert_eq!(mutex.lock_depth(), 1);
+                }
+                assert_eq!(mutex.lock_depth(), 0);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(mutex.lock().get(), THREADS * ITERATIONS);
+    assert_eq!(mutex.owner(), None);
+}
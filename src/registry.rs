@@ -0,0 +1,487 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! The registry of live threads and their metadata.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, Thread};
+use std::time::SystemTime;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use fork;
+use hooks;
+use id::ThreadId;
+
+/// Metadata about a live thread.
+///
+/// This is a snapshot; later changes to the thread, such as new tags, are not
+/// reflected in it.
+///
+/// With the `serde` feature, a `ThreadInfo` serializes as a struct with the
+/// fields `id`, `serial`, `os_tid`, `name`, `spawn_time`, `parent` and `tags`,
+/// named after its accessors. The spawn time uses the representation of serde
+/// for `SystemTime`, a struct of `secs_since_epoch` and `nanos_since_epoch`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ThreadInfo {
+    id: ThreadId,
+    serial: u64,
+    os_tid: Option<u32>,
+    name: Option<String>,
+    spawn_time: SystemTime,
+    parent: Option<ThreadId>,
+    tags: BTreeMap<String, String>,
+}
+
+impl ThreadInfo {
+    /// Returns the ID of the thread.
+    #[inline]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns the `serial()` of the thread.
+    ///
+    /// Serial numbers come from a monotonic counter, and a thread gets one
+    /// when it registers at the latest, so unlike `spawn_time()` they order
+    /// threads reliably: a thread spawned by a registered thread has a higher
+    /// serial number than its parent.
+    #[inline]
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+
+    /// Returns the kernel thread ID, on platforms that have `os_tid()`.
+    #[inline]
+    pub fn os_tid(&self) -> Option<u32> {
+        self.os_tid
+    }
+
+    /// Returns the name of the thread, if it has one.
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(|name| &name[..])
+    }
+
+    /// Returns the time at which the thread was registered.
+    ///
+    /// Threads register on their first call to `get()` or `current()`, so
+    /// this is an upper bound on the time the thread was spawned.
+    #[inline]
+    pub fn spawn_time(&self) -> SystemTime {
+        self.spawn_time
+    }
+
+    /// Returns the ID of the thread that spawned this thread, if known.
+    ///
+    /// The parent is known for threads spawned with `thread_id::spawn()` or
+    /// `thread_id::Builder`.
+    #[inline]
+    pub fn parent(&self) -> Option<ThreadId> {
+        self.parent
+    }
+
+    /// Returns the tags set with `set_tag()`, ordered by key.
+    #[inline]
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.tags
+    }
+
+    /// Returns the value of the tag with the given key, if it is set.
+    #[inline]
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).map(|value| &value[..])
+    }
+}
+
+/// The registered threads.
+#[derive(Default)]
+pub(crate) struct Entries {
+    /// Entries are replaced rather than mutated, so readers only hold the
+    /// lock for as long as it takes to clone an `Arc`.
+    infos: HashMap<ThreadId, Arc<ThreadInfo>>,
+    threads: HashMap<ThreadId, Thread>,
+    by_std: HashMap<thread::ThreadId, ThreadId>,
+}
+
+impl Entries {
+    fn insert(&mut self, info: ThreadInfo, thread: Thread) {
+        self.by_std.insert(thread.id(), info.id);
+        self.threads.insert(info.id, thread);
+        self.infos.insert(info.id, Arc::new(info));
+    }
+
+    fn remove(&mut self, id: ThreadId) -> Option<(Arc<ThreadInfo>, Thread)> {
+        let info = self.infos.remove(&id);
+        let thread = self.threads.remove(&id);
+        if let Some(ref thread) = thread {
+            self.by_std.remove(&thread.id());
+        }
+        info.zip(thread)
+    }
+}
+
+static REGISTRY: RwLock<Option<Entries>> = RwLock::new(None);
+
+fn read() -> RwLockReadGuard<'static, Option<Entries>> {
+    REGISTRY.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write() -> RwLockWriteGuard<'static, Option<Entries>> {
+    REGISTRY.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct Registration {
+    id: Cell<Option<ThreadId>>,
+    /// The parent to record when the thread registers.
+    parent: Cell<Option<ThreadId>>,
+    /// The name of the thread, cached when it registers, so it can be read
+    /// without the registry lock.
+    name: RefCell<Option<Arc<str>>>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(id) = self.id.get() {
+            hooks::run_exit(id);
+            if let Some(entries) = write().as_mut() {
+                entries.remove(id);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static REGISTRATION: Registration = const {
+        Registration {
+            id: Cell::new(None),
+            parent: Cell::new(None),
+            name: RefCell::new(None),
+        }
+    };
+}
+
+/// Registers the calling thread, if it is not registered yet.
+#[inline]
+pub(crate) fn touch(id: ThreadId) {
+    let _ = REGISTRATION.try_with(|registration| {
+        if registration.id.get().is_none() {
+            register(registration, id);
+        }
+    });
+}
+
+#[cold]
+fn register(registration: &Registration, id: ThreadId) {
+    fork::register();
+    registration.id.set(Some(id));
+    let thread = thread::current();
+    *registration.name.borrow_mut() = thread.name().map(Arc::from);
+    let info = ThreadInfo {
+        id,
+        serial: ::serial(),
+        os_tid: current_os_tid(),
+        name: thread.name().map(String::from),
+        spawn_time: SystemTime::now(),
+        parent: registration.parent.get(),
+        tags: BTreeMap::new(),
+    };
+    write()
+        .get_or_insert_with(Entries::default)
+        .insert(info, thread);
+    hooks::run_first_seen(id);
+}
+
+/// Returns the kernel thread ID of the calling thread, as `info()` reports it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn current_os_tid() -> Option<u32> {
+    Some(::os_tid())
+}
+
+/// Returns the kernel thread ID of the calling thread, as `info()` reports it.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn current_os_tid() -> Option<u32> {
+    None
+}
+
+/// Returns the name of the calling thread, as `info()` reports it, without
+/// taking the registry lock.
+///
+/// Returns `None` if the thread is not registered.
+pub(crate) fn current_name() -> Option<Arc<str>> {
+    REGISTRATION
+        .try_with(|registration| registration.name.borrow().clone())
+        .unwrap_or(None)
+}
+
+/// Replaces the entry of the calling thread with an updated copy.
+fn update_current<F: FnOnce(&mut ThreadInfo)>(f: F) {
+    let id = ::current();
+    let mut registry = write();
+    if let Some(entry) = registry
+        .as_mut()
+        .and_then(|entries| entries.infos.get_mut(&id))
+    {
+        f(Arc::make_mut(entry));
+    }
+}
+
+/// Returns metadata about the live thread with the given ID.
+///
+/// Threads register on their first call to `get()` or `current()`, and they
+/// are removed from the registry when they exit. Returns `None` for threads
+/// that are not registered.
+///
+/// The snapshot is shared with the registry rather than copied, so this only
+/// holds a read lock for as long as it takes to clone an `Arc`.
+pub fn info(id: ThreadId) -> Option<Arc<ThreadInfo>> {
+    read()
+        .as_ref()
+        .and_then(|entries| entries.infos.get(&id).cloned())
+}
+
+/// Returns the ID of the registered thread with the given standard library ID.
+///
+/// Threads register on their first call to `get()` or `current()`, so this
+/// returns `None` for threads that have not called into this crate, as well as
+/// for threads that have exited.
+pub fn from_std(std_id: thread::ThreadId) -> Option<ThreadId> {
+    read()
+        .as_ref()
+        .and_then(|entries| entries.by_std.get(&std_id).cloned())
+}
+
+/// Returns the standard library ID of the registered thread with the given ID.
+pub fn to_std(id: ThreadId) -> Option<thread::ThreadId> {
+    thread_handle(id).map(|thread| thread.id())
+}
+
+/// Returns the standard library handle of the registered thread with the given
+/// ID, for example to `unpark()` it.
+pub fn thread_handle(id: ThreadId) -> Option<Thread> {
+    read()
+        .as_ref()
+        .and_then(|entries| entries.threads.get(&id).cloned())
+}
+
+/// Registers the calling thread, which was just spawned by `parent`, and
+/// returns its ID.
+pub(crate) fn register_child(parent: ThreadId) -> ThreadId {
+    let _ = REGISTRATION.try_with(|registration| registration.parent.set(Some(parent)));
+    ::current()
+}
+
+/// Returns the ID of the thread that spawned the registered thread with the
+/// given ID, if it was spawned with `thread_id::spawn()` or
+/// `thread_id::Builder`.
+///
+/// The parent may have exited since.
+pub fn parent_of(id: ThreadId) -> Option<ThreadId> {
+    read()
+        .as_ref()
+        .and_then(|entries| entries.infos.get(&id).and_then(|info| info.parent))
+}
+
+/// Returns the registered threads that the thread with the given ID spawned,
+/// in the order they were spawned, by `ThreadInfo::serial()`.
+pub fn children_of(id: ThreadId) -> Vec<ThreadId> {
+    let mut children: Vec<_> = snapshot()
+        .into_iter()
+        .filter(|info| info.parent == Some(id))
+        .collect();
+    children.sort_by_key(|info| info.serial);
+    children.into_iter().map(|info| info.id).collect()
+}
+
+/// A registered thread and the registered threads it spawned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadTree {
+    info: ThreadInfo,
+    children: Vec<ThreadTree>,
+}
+
+impl ThreadTree {
+    /// Returns the thread at the root of this tree.
+    #[inline]
+    pub fn info(&self) -> &ThreadInfo {
+        &self.info
+    }
+
+    /// Returns the trees of the threads that this thread spawned, in the
+    /// order they were spawned.
+    #[inline]
+    pub fn children(&self) -> &[ThreadTree] {
+        &self.children
+    }
+}
+
+/// Returns all registered threads, arranged by which thread spawned which.
+///
+/// The roots are the threads without a known parent, and the threads whose
+/// parent has exited. Roots and children are ordered by
+/// `ThreadInfo::serial()`, which is the order they were spawned in.
+pub fn thread_tree() -> Vec<ThreadTree> {
+    let mut infos = snapshot();
+    infos.sort_by_key(|info| info.serial);
+    let serials: HashMap<ThreadId, u64> = infos.iter().map(|info| (info.id, info.serial)).collect();
+    let mut children: HashMap<ThreadId, Vec<Arc<ThreadInfo>>> = HashMap::new();
+    let mut roots = Vec::new();
+    for info in infos {
+        // A parent that registered after the child is a new thread that got
+        // the ID of the exited parent.
+        let parent = info.parent.filter(|parent| match serials.get(parent) {
+            Some(&serial) => serial < info.serial,
+            None => false,
+        });
+        match parent {
+            Some(parent) => children.entry(parent).or_default().push(info),
+            None => roots.push(info),
+        }
+    }
+
+    fn build(
+        info: Arc<ThreadInfo>,
+        children: &mut HashMap<ThreadId, Vec<Arc<ThreadInfo>>>,
+    ) -> ThreadTree {
+        let own = children.remove(&info.id).unwrap_or_default();
+        ThreadTree {
+            info: ThreadInfo::clone(&info),
+            children: own
+                .into_iter()
+                .map(|child| build(child, children))
+                .collect(),
+        }
+    }
+
+    roots
+        .into_iter()
+        .map(|root| build(root, &mut children))
+        .collect()
+}
+
+/// Returns the entries of all registered threads.
+pub(crate) fn snapshot() -> Vec<Arc<ThreadInfo>> {
+    match read().as_ref() {
+        Some(entries) => entries.infos.values().cloned().collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Sets a tag on the calling thread, which `info()` reports.
+///
+/// Replaces the previous value if the tag was set already.
+pub fn set_tag<K: Into<String>, V: Into<String>>(key: K, value: V) {
+    let (key, value) = (key.into(), value.into());
+    update_current(move |info| {
+        info.tags.insert(key, value);
+    });
+}
+
+pub(crate) fn lock_for_fork() -> RwLockWriteGuard<'static, Option<Entries>> {
+    write()
+}
+
+/// Removes all threads but the calling one, which is the only thread that
+/// survives in a forked child. The calling thread has a new kernel TID there,
+/// and depending on the backend, a new ID.
+pub(crate) fn after_fork_in_child(entries: &mut Option<Entries>) {
+    let own = REGISTRATION
+        .try_with(|registration| {
+            // A thread that never registered registers on its next call.
+            let old = registration.id.get()?;
+            // Ask the backend directly; `current()` would take the lock.
+            let new = ThreadId::from_raw(::backend::current());
+            registration.id.set(Some(new));
+            Some((old, new))
+        })
+        .unwrap_or(None);
+    if let Some(entries) = entries.as_mut() {
+        let entry = own.and_then(|(old, _)| entries.remove(old));
+        *entries = Entries::default();
+        if let (Some((info, thread)), Some((_, new))) = (entry, own) {
+            let mut info = ThreadInfo::clone(&info);
+            info.id = new;
+            info.os_tid = current_os_tid();
+            entries.insert(info, thread);
+        }
+    }
+}
+
+#[test]
+fn registry_tracks_live_threads() {
+    use std::sync::mpsc;
+
+    let (id_tx, id_rx) = mpsc::channel();
+    let (exit_tx, exit_rx) = mpsc::channel::<()>();
+    let handle = thread::Builder::new()
+        .name("registry-test".to_string())
+        .spawn(move || {
+            set_tag("role", "worker");
+            id_tx.send(::current()).unwrap();
+            exit_rx.recv().unwrap();
+        })
+        .unwrap();
+
+    let id = id_rx.recv().unwrap();
+    let info = info(id).expect("live thread should be registered");
+    assert_eq!(info.id(), id);
+    assert_eq!(info.name(), Some("registry-test"));
+    assert_eq!(info.tag("role"), Some("worker"));
+    assert!(info.spawn_time() <= SystemTime::now());
+
+    exit_tx.send(()).unwrap();
+    handle.join().unwrap();
+    // The platform may have given the ID to a new thread already.
+    let name = ::registry::info(id).and_then(|info| info.name().map(String::from));
+    assert_ne!(name, Some("registry-test".to_string()));
+}
+
+#[test]
+fn std_ids_map_to_registered_threads() {
+    use std::sync::mpsc;
+
+    let (id_tx, id_rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        id_tx.send(::current()).unwrap();
+        // Wait until the main thread unparks us by our crate ID.
+        thread::park();
+    });
+
+    let id = id_rx.recv().unwrap();
+    let std_id = handle.thread().id();
+    assert_eq!(from_std(std_id), Some(id));
+    assert_eq!(to_std(id), Some(std_id));
+    thread_handle(id).unwrap().unpark();
+    handle.join().unwrap();
+
+    // The std ID is never reused, so the mapping is gone for good.
+    assert_eq!(from_std(std_id), None);
+    let me = ::current();
+    assert_eq!(from_std(thread::current().id()), Some(me));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn thread_info_round_trips_through_serde() {
+    set_tag("role", "serializer");
+    let info = info(::current()).unwrap();
+    let info = ThreadInfo::clone(&info);
+
+    let json = serde_json::to_value(&info).unwrap();
+    assert_eq!(json["id"], info.id().as_u64());
+    assert_eq!(json["serial"], info.serial());
+    assert_eq!(json["tags"]["role"], "serializer");
+    assert!(json["spawn_time"]["secs_since_epoch"].is_u64());
+    assert_eq!(serde_json::from_value::<ThreadInfo>(json).unwrap(), info);
+
+    let bytes = bincode::serialize(&info).unwrap();
+    assert_eq!(bincode::deserialize::<ThreadInfo>(&bytes).unwrap(), info);
+}
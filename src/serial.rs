@@ -0,0 +1,68 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Monotonic thread serial numbers.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use fork;
+
+/// The next serial number to hand out. Zero is reserved to mean "unassigned".
+static NEXT_SERIAL: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static SERIAL: Cell<u64> = const { Cell::new(0) };
+}
+
+fn next_serial() -> u64 {
+    NEXT_SERIAL.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Clears the cache, so the surviving thread in a forked child gets a serial
+/// number that differs from the one of the forking thread.
+pub(crate) fn after_fork_in_child() {
+    let _ = SERIAL.try_with(|cached| cached.set(0));
+}
+
+/// Returns a number that is unique to the calling thread for the lifetime of
+/// the process.
+///
+/// Unlike `get()`, whose values the platform reuses after a thread exits, a
+/// serial number is never handed out twice. Serial numbers are assigned from a
+/// process-wide counter on the first call from a thread, so threads that call
+/// `serial()` earlier get lower numbers. They are never zero.
+#[inline]
+pub fn serial() -> u64 {
+    SERIAL
+        .try_with(|cached| {
+            let serial = cached.get();
+            if serial != 0 {
+                return serial;
+            }
+            fork::register();
+            let serial = next_serial();
+            cached.set(serial);
+            serial
+        })
+        // During thread teardown the cache may be gone. A fresh number is
+        // still unique, it just differs from the one the thread had before.
+        .unwrap_or_else(|_| next_serial())
+}
+
+#[test]
+fn serials_are_never_reused() {
+    use std::thread;
+
+    let main_serial = serial();
+    assert_eq!(main_serial, serial());
+
+    let first = thread::spawn(serial).join().unwrap();
+    let second = thread::spawn(serial).join().unwrap();
+    assert!(first != main_serial);
+    assert!(second > first);
+}
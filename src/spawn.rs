@@ -0,0 +1,177 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Spawning threads whose ID is known to the parent.
+
+use std::io;
+use std::sync::mpsc;
+use std::thread::{self, Thread};
+
+use id::ThreadId;
+use registry;
+
+/// A wrapper around `std::thread::Builder` that makes the ID of the spawned
+/// thread available to the spawning thread, and records it as the parent.
+#[derive(Debug)]
+pub struct Builder {
+    inner: thread::Builder,
+}
+
+/// A handle to a thread spawned with `thread_id::spawn()` or `Builder`.
+#[derive(Debug)]
+pub struct JoinHandle<T> {
+    inner: thread::JoinHandle<T>,
+    id: ThreadId,
+}
+
+impl Builder {
+    /// Creates a builder with the defaults of `std::thread::Builder`.
+    pub fn new() -> Builder {
+        Builder {
+            inner: thread::Builder::new(),
+        }
+    }
+
+    /// Names the thread.
+    pub fn name(self, name: String) -> Builder {
+        Builder {
+            inner: self.inner.name(name),
+        }
+    }
+
+    /// Sets the size of the stack of the thread, in bytes.
+    pub fn stack_size(self, size: usize) -> Builder {
+        Builder {
+            inner: self.inner.stack_size(size),
+        }
+    }
+
+    /// Spawns the thread, and waits until it has registered.
+    ///
+    /// The thread is registered with the calling thread as its parent before
+    /// `f` runs, so `parent_of()` and `thread_tree()` include it, and the ID
+    /// is available right away from `JoinHandle::id()`.
+    pub fn spawn<F, T>(self, f: F) -> io::Result<JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let parent = ::current();
+        let (id_tx, id_rx) = mpsc::sync_channel(1);
+        let inner = self.inner.spawn(move || {
+            // The parent is blocked on this, so it cannot have hung up.
+            let _ = id_tx.send(registry::register_child(parent));
+            f()
+        })?;
+        let id = id_rx
+            .recv()
+            .expect("spawned thread exited before registering");
+        Ok(JoinHandle { inner, id })
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+/// Spawns a thread with the calling thread as its parent, like
+/// `std::thread::spawn`, and returns a handle that knows its ID.
+///
+/// # Panics
+///
+/// Panics if the operating system fails to create the thread; use `Builder`
+/// to handle that error.
+pub fn spawn<F, T>(f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f).expect("failed to spawn thread")
+}
+
+impl<T> JoinHandle<T> {
+    /// Returns the ID of the spawned thread.
+    #[inline]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns the standard library handle of the spawned thread.
+    #[inline]
+    pub fn thread(&self) -> &Thread {
+        self.inner.thread()
+    }
+
+    /// Returns whether the thread has finished running its closure.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.inner.is_finished()
+    }
+
+    /// Waits for the thread to finish, and returns the result of its closure,
+    /// like `std::thread::JoinHandle::join`.
+    pub fn join(self) -> thread::Result<T> {
+        self.inner.join()
+    }
+
+    /// Returns the underlying standard library handle.
+    pub fn into_inner(self) -> thread::JoinHandle<T> {
+        self.inner
+    }
+}
+
+#[test]
+fn spawned_threads_record_their_parent() {
+    use std::sync::mpsc;
+
+    let parent = ::current();
+    let (exit_tx, exit_rx) = mpsc::channel::<()>();
+    let child = Builder::new()
+        .name("spawn-test".to_string())
+        .spawn(move || {
+            let grandchild = spawn(move || {
+                exit_rx.recv().unwrap();
+                ::current()
+            });
+            assert_eq!(::parent_of(grandchild.id()), Some(::current()));
+            (::current(), grandchild.join().unwrap())
+        })
+        .unwrap();
+
+    let id = child.id();
+    assert_eq!(::parent_of(id), Some(parent));
+    assert!(::info(id).unwrap().serial() > ::info(parent).unwrap().serial());
+    assert_eq!(::info(id).unwrap().name(), Some("spawn-test"));
+    assert_eq!(::to_std(id), Some(child.thread().id()));
+
+    // Wait until the grandchild is registered, then inspect the tree while
+    // the grandchild is blocked.
+    let grandchild = loop {
+        if let Some(&grandchild) = ::children_of(id).first() {
+            break grandchild;
+        }
+        thread::yield_now();
+    };
+    assert!(::children_of(parent).contains(&id));
+    let tree = ::thread_tree();
+    let root = tree
+        .iter()
+        .find(|node| node.info().id() == parent)
+        .expect("the test thread is a root");
+    let node = root
+        .children()
+        .iter()
+        .find(|node| node.info().id() == id)
+        .expect("the child is under the test thread");
+    assert_eq!(node.children()[0].info().id(), grandchild);
+    assert_eq!(node.children()[0].info().parent(), Some(id));
+
+    exit_tx.send(()).unwrap();
+    assert_eq!(child.join().unwrap(), (id, grandchild));
+}
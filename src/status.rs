@@ -0,0 +1,311 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Per-thread status from `/proc` on Linux.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+/// The scheduling state of a thread, as reported by the kernel.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ThreadState {
+    /// Running or runnable (`R`).
+    Running,
+    /// Sleeping in an interruptible wait (`S`).
+    Sleeping,
+    /// Waiting in an uninterruptible disk sleep (`D`).
+    DiskSleep,
+    /// Exited, but not yet reaped (`Z`).
+    Zombie,
+    /// Stopped by a signal (`T`).
+    Stopped,
+    /// Stopped by a debugger (`t`).
+    TracingStop,
+    /// Dead (`X`).
+    Dead,
+    /// An idle kernel thread (`I`).
+    Idle,
+    /// A state that this crate does not know about.
+    Other(char),
+}
+
+impl ThreadState {
+    fn from_char(c: char) -> ThreadState {
+        match c {
+            'R' => ThreadState::Running,
+            'S' => ThreadState::Sleeping,
+            'D' => ThreadState::DiskSleep,
+            'Z' => ThreadState::Zombie,
+            'T' => ThreadState::Stopped,
+            't' => ThreadState::TracingStop,
+            'X' | 'x' => ThreadState::Dead,
+            'I' => ThreadState::Idle,
+            other => ThreadState::Other(other),
+        }
+    }
+}
+
+/// The error returned when reading the status of a thread fails.
+#[derive(Debug)]
+pub enum StatusError {
+    /// The thread does not exist, most likely because it has exited.
+    Exited,
+    /// Reading from `/proc` failed for a different reason.
+    Io(io::Error),
+    /// The contents of `/proc` could not be parsed. Holds the name of the field
+    /// that was missing or malformed.
+    Malformed(&'static str),
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StatusError::Exited => f.write_str("thread has exited"),
+            StatusError::Io(ref err) => write!(f, "failed to read thread status: {}", err),
+            StatusError::Malformed(field) => write!(f, "malformed thread status field '{}'", field),
+        }
+    }
+}
+
+impl Error for StatusError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            StatusError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StatusError {
+    fn from(err: io::Error) -> StatusError {
+        // ESRCH happens when the thread exits while its files are being read.
+        if err.kind() == io::ErrorKind::NotFound || err.raw_os_error() == Some(libc::ESRCH) {
+            StatusError::Exited
+        } else {
+            StatusError::Io(err)
+        }
+    }
+}
+
+/// A snapshot of the status of a thread, read from `/proc`.
+///
+/// CPU times are in clock ticks, see `sysconf(_SC_CLK_TCK)`. Signal masks have
+/// bit `n - 1` set for signal number `n`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadStatus {
+    comm: String,
+    state: ThreadState,
+    user_time: u64,
+    system_time: u64,
+    voluntary_switches: u64,
+    involuntary_switches: u64,
+    last_cpu: u32,
+    nice: i32,
+    signals_pending: u64,
+    signals_shared_pending: u64,
+    signals_blocked: u64,
+    signals_ignored: u64,
+    signals_caught: u64,
+}
+
+impl ThreadStatus {
+    /// Reads the status of the thread with the given kernel TID, which must be
+    /// a thread of the current process.
+    pub fn read(os_tid: u32) -> Result<ThreadStatus, StatusError> {
+        let dir = format!("/proc/self/task/{}", os_tid);
+        let stat = fs::read_to_string(format!("{}/stat", dir))?;
+        let status = fs::read_to_string(format!("{}/status", dir))?;
+        ThreadStatus::parse(&stat, &status)
+    }
+
+    /// Parses the contents of the `stat` and `status` files of a thread.
+    pub fn parse(stat: &str, status: &str) -> Result<ThreadStatus, StatusError> {
+        let comm_start = stat.find('(').ok_or(StatusError::Malformed("comm"))?;
+        let comm_end = stat.rfind(')').ok_or(StatusError::Malformed("comm"))?;
+        let comm = stat
+            .get(comm_start + 1..comm_end)
+            .ok_or(StatusError::Malformed("comm"))?;
+        let state = stat_field(stat, 3)
+            .and_then(|state| state.chars().next())
+            .ok_or(StatusError::Malformed("state"))?;
+
+        Ok(ThreadStatus {
+            comm: comm.to_string(),
+            state: ThreadState::from_char(state),
+            user_time: parse_stat(stat, 14, "utime")?,
+            system_time: parse_stat(stat, 15, "stime")?,
+            nice: parse_stat(stat, 19, "nice")?,
+            last_cpu: parse_stat(stat, 39, "processor")?,
+            voluntary_switches: parse_status(status, "voluntary_ctxt_switches")?,
+            involuntary_switches: parse_status(status, "nonvoluntary_ctxt_switches")?,
+            signals_pending: parse_status_mask(status, "SigPnd")?,
+            signals_shared_pending: parse_status_mask(status, "ShdPnd")?,
+            signals_blocked: parse_status_mask(status, "SigBlk")?,
+            signals_ignored: parse_status_mask(status, "SigIgn")?,
+            signals_caught: parse_status_mask(status, "SigCgt")?,
+        })
+    }
+
+    /// Returns the command name of the thread, truncated to 15 bytes.
+    #[inline]
+    pub fn comm(&self) -> &str {
+        &self.comm
+    }
+
+    /// Returns the scheduling state of the thread.
+    #[inline]
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    /// Returns the time the thread has spent in user mode, in clock ticks.
+    #[inline]
+    pub fn user_time(&self) -> u64 {
+        self.user_time
+    }
+
+    /// Returns the time the thread has spent in kernel mode, in clock ticks.
+    #[inline]
+    pub fn system_time(&self) -> u64 {
+        self.system_time
+    }
+
+    /// Returns the number of times the thread yielded the CPU voluntarily.
+    #[inline]
+    pub fn voluntary_switches(&self) -> u64 {
+        self.voluntary_switches
+    }
+
+    /// Returns the number of times the thread was preempted.
+    #[inline]
+    pub fn involuntary_switches(&self) -> u64 {
+        self.involuntary_switches
+    }
+
+    /// Returns the CPU that the thread last ran on.
+    #[inline]
+    pub fn last_cpu(&self) -> u32 {
+        self.last_cpu
+    }
+
+    /// Returns the nice value of the thread, from 19 (low priority) to -20.
+    #[inline]
+    pub fn nice(&self) -> i32 {
+        self.nice
+    }
+
+    /// Returns the signals pending for the thread.
+    #[inline]
+    pub fn signals_pending(&self) -> u64 {
+        self.signals_pending
+    }
+
+    /// Returns the signals pending for the process as a whole.
+    #[inline]
+    pub fn signals_shared_pending(&self) -> u64 {
+        self.signals_shared_pending
+    }
+
+    /// Returns the signals blocked by the thread.
+    #[inline]
+    pub fn signals_blocked(&self) -> u64 {
+        self.signals_blocked
+    }
+
+    /// Returns the signals ignored by the process.
+    #[inline]
+    pub fn signals_ignored(&self) -> u64 {
+        self.signals_ignored
+    }
+
+    /// Returns the signals for which the process has a handler.
+    #[inline]
+    pub fn signals_caught(&self) -> u64 {
+        self.signals_caught
+    }
+}
+
+/// Returns field `n` of a `stat` file, numbered from 1 as in `proc(5)`.
+///
+/// Only works for fields after the command name, so `n` must be at least 3.
+pub(crate) fn stat_field(stat: &str, n: usize) -> Option<&str> {
+    // The command name in parentheses may contain spaces and parentheses, so
+    // count fields from the last closing parenthesis, which field 3 follows.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(n - 3)
+}
+
+fn parse_stat<T: FromStr>(stat: &str, n: usize, name: &'static str) -> Result<T, StatusError> {
+    stat_field(stat, n)
+        .and_then(|field| field.parse().ok())
+        .ok_or(StatusError::Malformed(name))
+}
+
+fn status_field<'a>(status: &'a str, key: &str) -> Option<&'a str> {
+    status.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        if k == key {
+            Some(v.trim())
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_status(status: &str, key: &'static str) -> Result<u64, StatusError> {
+    status_field(status, key)
+        .and_then(|field| field.parse().ok())
+        .ok_or(StatusError::Malformed(key))
+}
+
+fn parse_status_mask(status: &str, key: &'static str) -> Result<u64, StatusError> {
+    status_field(status, key)
+        .and_then(|field| u64::from_str_radix(field, 16).ok())
+        .ok_or(StatusError::Malformed(key))
+}
+
+#[test]
+fn thread_status_parses_fixtures() {
+    let stat = include_str!("../tests/fixtures/task-stat");
+    let status = include_str!("../tests/fixtures/task-status");
+    let parsed = ThreadStatus::parse(stat, status).unwrap();
+    assert_eq!(parsed.comm(), "my (worker) 1");
+    assert_eq!(parsed.state(), ThreadState::Sleeping);
+    assert_eq!(parsed.user_time(), 1234);
+    assert_eq!(parsed.system_time(), 56);
+    assert_eq!(parsed.nice(), -5);
+    assert_eq!(parsed.last_cpu(), 3);
+    assert_eq!(parsed.voluntary_switches(), 8123);
+    assert_eq!(parsed.involuntary_switches(), 42);
+    assert_eq!(parsed.signals_pending(), 0x100);
+    assert_eq!(parsed.signals_shared_pending(), 0x4000);
+    assert_eq!(parsed.signals_blocked(), 0xfffffffe7ffbfeff);
+    assert_eq!(parsed.signals_ignored(), 0x1000);
+    assert_eq!(parsed.signals_caught(), 0x100004a02);
+
+    match ThreadStatus::parse("6081 (x) S 1", status) {
+        Err(StatusError::Malformed("utime")) => {}
+        other => panic!("expected malformed utime, got {:?}", other),
+    }
+}
+
+#[test]
+fn thread_status_of_exited_thread_is_an_error() {
+    use std::thread;
+
+    let own = ThreadStatus::read(::os_tid()).unwrap();
+    assert_eq!(own.state(), ThreadState::Running);
+
+    let tid = thread::spawn(::os_tid).join().unwrap();
+    match ThreadStatus::read(tid) {
+        Err(StatusError::Exited) => {}
+        other => panic!("expected exited thread, got {:?}", other),
+    }
+}
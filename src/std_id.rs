@@ -0,0 +1,65 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Converting `std::thread::ThreadId` to an integer.
+
+use std::thread;
+
+/// Returns the integer value of a standard library thread ID.
+///
+/// This uses the unstable `ThreadId::as_u64` where it is enabled, on the
+/// Switch target or with the `nightly` feature.
+#[cfg(any(target_os = "switch", feature = "nightly"))]
+#[inline]
+pub(crate) fn to_u64(id: thread::ThreadId) -> u64 {
+    id.as_u64().get()
+}
+
+/// Returns the integer value of a standard library thread ID.
+///
+/// On stable Rust the value is not exposed, but the `Debug` output of
+/// `ThreadId` has been `ThreadId(n)` since it was introduced, with the same
+/// `n` as `as_u64` returns. Should the format ever change, this falls back to a
+/// hash, which is still stable per thread and unique in practice.
+#[cfg(not(any(target_os = "switch", feature = "nightly")))]
+pub(crate) fn to_u64(id: thread::ThreadId) -> u64 {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    parse_debug(&format!("{:?}", id)).unwrap_or_else(|| {
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        // Zero is not a valid ID.
+        hasher.finish() | 1
+    })
+}
+
+/// Extracts `n` from `ThreadId(n)`.
+#[cfg_attr(any(target_os = "switch", feature = "nightly"), allow(dead_code))]
+fn parse_debug(debug: &str) -> Option<u64> {
+    debug
+        .strip_prefix("ThreadId(")?
+        .strip_suffix(')')?
+        .parse()
+        .ok()
+        .filter(|&n| n != 0)
+}
+
+#[test]
+fn std_thread_ids_convert_to_distinct_integers() {
+    let main = to_u64(thread::current().id());
+    assert_eq!(main, to_u64(thread::current().id()));
+    assert!(main != 0);
+    let other = thread::spawn(|| to_u64(thread::current().id()))
+        .join()
+        .unwrap();
+    assert!(main != other);
+
+    assert_eq!(parse_debug("ThreadId(17)"), Some(17));
+    assert_eq!(parse_debug("ThreadId(0)"), None);
+    assert_eq!(parse_debug("ThreadId { id: 17 }"), None);
+}
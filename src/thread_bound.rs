@@ -0,0 +1,366 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! Values that may only be used on the thread that created them.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::mem::{self, ManuallyDrop};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use id::ThreadId;
+
+/// What happens to a `ThreadBound` value that is dropped on a different thread
+/// than the one that created it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum DropPolicy {
+    /// The value is never dropped.
+    Leak,
+    /// The value is queued, and dropped on the thread that created it the
+    /// next time that thread calls `drop_returned()`, creates a `ThreadBound`
+    /// with this policy, or exits. If that thread has exited already, the
+    /// value is leaked.
+    SendBack,
+}
+
+/// A value that may only be accessed on the thread that created it.
+///
+/// `ThreadBound` is `Send` and `Sync` whatever the value is, so it can be moved
+/// around and stored in shared structures, but every access checks that the
+/// calling thread is the one that created it. Dereferencing it on a different
+/// thread panics, and `try_get` returns an error. This suits handles into
+/// libraries that require all calls to come from one thread.
+///
+/// Ownership is checked with `serial()`, so a thread that happens to get the
+/// `ThreadId` of an exited owner cannot access the value.
+pub struct ThreadBound<T> {
+    value: ManuallyDrop<T>,
+    owner: ThreadId,
+    serial: u64,
+    policy: DropPolicy,
+    drop_foreign: fn(T, u64),
+}
+
+unsafe impl<T> Send for ThreadBound<T> {}
+unsafe impl<T> Sync for ThreadBound<T> {}
+
+/// The error returned when a `ThreadBound` is accessed on a different thread
+/// than the one that created it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WrongThreadError {
+    owner: ThreadId,
+    current: ThreadId,
+}
+
+impl WrongThreadError {
+    /// Returns the thread that created the value.
+    #[inline]
+    pub fn owner(&self) -> ThreadId {
+        self.owner
+    }
+
+    /// Returns the thread that tried to access the value.
+    #[inline]
+    pub fn current(&self) -> ThreadId {
+        self.current
+    }
+}
+
+impl fmt::Display for WrongThreadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "value owned by thread {} accessed from thread {}",
+            self.owner, self.current
+        )
+    }
+}
+
+impl Error for WrongThreadError {}
+
+impl<T> ThreadBound<T> {
+    /// Binds `value` to the calling thread, leaking it if it is dropped on a
+    /// different thread.
+    pub fn new(value: T) -> ThreadBound<T> {
+        ThreadBound {
+            value: ManuallyDrop::new(value),
+            owner: ::current(),
+            serial: ::serial(),
+            policy: DropPolicy::Leak,
+            drop_foreign: leak,
+        }
+    }
+
+    /// Binds `value` to the calling thread, with the given drop policy.
+    pub fn with_policy(value: T, policy: DropPolicy) -> ThreadBound<T>
+    where
+        T: 'static,
+    {
+        let mut bound = ThreadBound::new(value);
+        if policy == DropPolicy::SendBack {
+            drop_returned();
+            accept_returned(bound.serial);
+            bound.policy = policy;
+            bound.drop_foreign = send_back::<T>;
+        }
+        bound
+    }
+
+    /// Returns the thread that created the value.
+    #[inline]
+    pub fn owner(&self) -> ThreadId {
+        self.owner
+    }
+
+    /// Returns the drop policy.
+    #[inline]
+    pub fn policy(&self) -> DropPolicy {
+        self.policy
+    }
+
+    /// Returns whether the calling thread created the value.
+    #[inline]
+    pub fn is_owner(&self) -> bool {
+        ::serial() == self.serial
+    }
+
+    #[cold]
+    fn wrong_thread(&self) -> WrongThreadError {
+        WrongThreadError {
+            owner: self.owner,
+            current: ::current(),
+        }
+    }
+
+    /// Returns the value, or an error if the calling thread did not create it.
+    #[inline]
+    pub fn try_get(&self) -> Result<&T, WrongThreadError> {
+        if self.is_owner() {
+            Ok(&self.value)
+        } else {
+            Err(self.wrong_thread())
+        }
+    }
+
+    /// Returns the value mutably, or an error if the calling thread did not
+    /// create it.
+    #[inline]
+    pub fn try_get_mut(&mut self) -> Result<&mut T, WrongThreadError> {
+        if self.is_owner() {
+            Ok(&mut self.value)
+        } else {
+            Err(self.wrong_thread())
+        }
+    }
+
+    /// Returns the value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the calling thread did not create the value.
+    #[inline]
+    pub fn get(&self) -> &T {
+        self.try_get().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Returns the value mutably.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the calling thread did not create the value.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        self.try_get_mut().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Unwraps the value, or returns `self` if the calling thread did not
+    /// create it.
+    pub fn try_into_inner(self) -> Result<T, ThreadBound<T>> {
+        if !self.is_owner() {
+            return Err(self);
+        }
+        let mut bound = ManuallyDrop::new(self);
+        Ok(unsafe { ManuallyDrop::take(&mut bound.value) })
+    }
+}
+
+impl<T> Deref for ThreadBound<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.get()
+    }
+}
+
+impl<T> DerefMut for ThreadBound<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.get_mut()
+    }
+}
+
+impl<T> Drop for ThreadBound<T> {
+    fn drop(&mut self) {
+        let value = unsafe { ManuallyDrop::take(&mut self.value) };
+        if self.is_owner() {
+            drop(value);
+        } else {
+            (self.drop_foreign)(value, self.serial);
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ThreadBound<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("ThreadBound");
+        match self.try_get() {
+            Ok(value) => d.field("value", value),
+            Err(_) => d.field("value", &format_args!("<other thread>")),
+        };
+        d.field("owner", &self.owner)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// A value dropped on a foreign thread, waiting to be dropped on its origin.
+pub(crate) struct Returned(Box<dyn FnOnce()>);
+
+// The closure is only called on the thread that created the value.
+unsafe impl Send for Returned {}
+
+/// The values sent back to every thread that accepts them, by serial number.
+pub(crate) type Queues = HashMap<u64, Vec<Returned>>;
+
+static RETURNED: Mutex<Option<Queues>> = Mutex::new(None);
+
+/// Drops the values sent back to the thread when it exits, and stops accepting
+/// new ones.
+struct Origin {
+    serial: Cell<u64>,
+}
+
+impl Drop for Origin {
+    fn drop(&mut self) {
+        let returned = lock().as_mut().and_then(|q| q.remove(&self.serial.get()));
+        drop(returned);
+    }
+}
+
+thread_local! {
+    static ORIGIN: Origin = const { Origin { serial: Cell::new(0) } };
+}
+
+fn lock() -> MutexGuard<'static, Option<Queues>> {
+    RETURNED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn leak<T>(value: T, _serial: u64) {
+    mem::forget(value);
+}
+
+fn send_back<T: 'static>(value: T, serial: u64) {
+    let mut queues = lock();
+    match queues.as_mut().and_then(|q| q.get_mut(&serial)) {
+        Some(queue) => queue.push(Returned(Box::new(move || drop(value)))),
+        None => mem::forget(value),
+    }
+}
+
+/// Starts accepting values sent back to the calling thread.
+fn accept_returned(serial: u64) {
+    let registered = ORIGIN.try_with(|origin| {
+        if origin.serial.get() == 0 {
+            origin.serial.set(serial);
+        }
+    });
+    // Without the destructor, nothing would drop the queue when the thread
+    // exits, so values are leaked instead.
+    if registered.is_ok() {
+        lock()
+            .get_or_insert_with(HashMap::new)
+            .entry(serial)
+            .or_default();
+    }
+}
+
+/// Drops the `ThreadBound` values that were created on the calling thread with
+/// `DropPolicy::SendBack` and have been dropped on other threads since.
+///
+/// Returns the number of values dropped. Threads that create such values and
+/// live long, like event loops, should call this periodically.
+pub fn drop_returned() -> usize {
+    let serial = ::serial();
+    let returned = match lock().as_mut().and_then(|q| q.get_mut(&serial)) {
+        Some(queue) => mem::take(queue),
+        None => return 0,
+    };
+    // Run the destructors without holding the lock, as they may drop more
+    // `ThreadBound` values.
+    let count = returned.len();
+    for Returned(drop_value) in returned {
+        drop_value();
+    }
+    count
+}
+
+#[cfg(unix)]
+pub(crate) fn lock_for_fork() -> MutexGuard<'static, Option<Queues>> {
+    lock()
+}
+
+/// Forgets all queues, because their threads do not exist in a forked child.
+/// The surviving thread has a new serial number, so its old queue is gone too.
+#[cfg(unix)]
+pub(crate) fn after_fork_in_child(queues: &mut Option<Queues>) {
+    // The values belong to threads of the parent, so they must not be dropped.
+    mem::forget(queues.take());
+}
+
+#[test]
+fn thread_bound_rejects_other_threads() {
+    use std::thread;
+
+    let mut bound = ThreadBound::new(vec![1]);
+    bound.push(2);
+    assert_eq!(*bound, [1, 2]);
+    assert_eq!(bound.owner(), ::current());
+
+    let bound = thread::spawn(move || {
+        let err = bound.try_get().unwrap_err();
+        assert_eq!(err.owner(), bound.owner());
+        assert_eq!(err.current(), ::current());
+        bound
+    })
+    .join()
+    .unwrap();
+    assert_eq!(bound.try_into_inner().unwrap(), [1, 2]);
+
+    let bound = ThreadBound::new(0);
+    let panicked = thread::spawn(move || *bound).join().is_err();
+    assert!(panicked);
+}
+
+#[test]
+fn thread_bound_is_sent_back_to_its_origin() {
+    use std::rc::Rc;
+    use std::thread;
+
+    let rc = Rc::new(());
+    let bound = ThreadBound::with_policy(rc.clone(), DropPolicy::SendBack);
+    thread::spawn(move || drop(bound)).join().unwrap();
+    assert_eq!(Rc::strong_count(&rc), 2);
+    assert_eq!(drop_returned(), 1);
+    assert_eq!(Rc::strong_count(&rc), 1);
+
+    let bound = ThreadBound::new(rc.clone());
+    thread::spawn(move || drop(bound)).join().unwrap();
+    assert_eq!(drop_returned(), 0);
+    assert_eq!(Rc::strong_count(&rc), 2);
+}
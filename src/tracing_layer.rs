@@ -0,0 +1,305 @@
+// Thread-ID -- Get a unique thread ID
+// Copyright 2016 Ruud van Asseldonk
+//
+// Licensed under either the Apache License, Version 2.0, or the MIT license, at
+// your option. A copy of both licenses has been included in the root of the
+// repository.
+
+//! A `tracing` layer that records the identity of threads on spans and events.
+
+use std::fmt;
+use std::sync::Arc;
+
+use tracing_core::span::{Attributes, Id};
+use tracing_core::{Event, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use id::ThreadId;
+use registry;
+
+/// The identity of a thread, as recorded by `ThreadLayer`.
+///
+/// Formats as `thread.id=… thread.os_tid=… thread.name=…`, where the kernel
+/// thread ID and the name are left out if they are not known.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadFields {
+    id: ThreadId,
+    os_tid: Option<u32>,
+    name: Option<Arc<str>>,
+}
+
+impl ThreadFields {
+    /// Returns the fields of the calling thread.
+    pub fn current() -> ThreadFields {
+        // This registers the thread, which caches its name.
+        let id = ::current();
+        // Read from per-thread caches; this runs for every event.
+        ThreadFields {
+            id,
+            os_tid: registry::current_os_tid(),
+            name: registry::current_name(),
+        }
+    }
+
+    /// Returns the `thread.id` field.
+    #[inline]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Returns the `thread.os_tid` field, the kernel thread ID.
+    #[inline]
+    pub fn os_tid(&self) -> Option<u32> {
+        self.os_tid
+    }
+
+    /// Returns the `thread.name` field.
+    #[inline]
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+impl fmt::Display for ThreadFields {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "thread.id={}", self.id)?;
+        if let Some(os_tid) = self.os_tid {
+            write!(f, " thread.os_tid={}", os_tid)?;
+        }
+        if let Some(ref name) = self.name {
+            write!(f, " thread.name={:?}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// A thread other than the creator that entered a span.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandOff {
+    thread: ThreadFields,
+    entries: u64,
+}
+
+impl HandOff {
+    /// Returns the thread that entered the span.
+    #[inline]
+    pub fn thread(&self) -> &ThreadFields {
+        &self.thread
+    }
+
+    /// Returns how many times the thread entered the span.
+    #[inline]
+    pub fn entries(&self) -> u64 {
+        self.entries
+    }
+}
+
+/// The threads involved with a span, stored in its extensions by
+/// `ThreadLayer`.
+#[derive(Clone, Debug)]
+pub struct SpanThreads {
+    created: ThreadFields,
+    hand_offs: Vec<HandOff>,
+}
+
+impl SpanThreads {
+    /// Returns the thread that created the span.
+    #[inline]
+    pub fn created(&self) -> &ThreadFields {
+        &self.created
+    }
+
+    /// Returns the other threads that entered the span, in the order they
+    /// first entered it.
+    #[inline]
+    pub fn hand_offs(&self) -> &[HandOff] {
+        &self.hand_offs
+    }
+}
+
+/// A layer that records the thread that creates every span, and every other
+/// thread that enters it.
+///
+/// The record is a `SpanThreads` in the extensions of the span, where other
+/// layers and formatters can look it up. Layers cannot add fields to events,
+/// so to include the thread fields in formatted events, wrap the event format
+/// in a `ThreadEventFormat`:
+///
+/// ```ignore
+/// use tracing_subscriber::fmt::format::Format;
+/// use tracing_subscriber::prelude::*;
+///
+/// tracing_subscriber::registry()
+///     .with(thread_id::ThreadLayer::new())
+///     .with(tracing_subscriber::fmt::layer().event_format(
+///         thread_id::ThreadEventFormat::new(Format::default()),
+///     ))
+///     .init();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ThreadLayer {
+    _private: (),
+}
+
+impl ThreadLayer {
+    /// Creates the layer.
+    pub fn new() -> ThreadLayer {
+        ThreadLayer { _private: () }
+    }
+}
+
+impl<S> Layer<S> for ThreadLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanThreads {
+                created: ThreadFields::current(),
+                hand_offs: Vec::new(),
+            });
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let current = ::current();
+        let mut extensions = span.extensions_mut();
+        let threads = match extensions.get_mut::<SpanThreads>() {
+            Some(threads) if threads.created.id != current => threads,
+            _ => return,
+        };
+        match threads
+            .hand_offs
+            .iter_mut()
+            .find(|hand_off| hand_off.thread.id == current)
+        {
+            Some(hand_off) => hand_off.entries += 1,
+            None => threads.hand_offs.push(HandOff {
+                thread: ThreadFields::current(),
+                entries: 1,
+            }),
+        }
+    }
+}
+
+/// An event format that prefixes events with the fields of the thread that
+/// emits them.
+///
+/// When the event is in a span that was created on a different thread, the ID
+/// of that thread follows as `span.thread.id`.
+#[derive(Clone, Debug, Default)]
+pub struct ThreadEventFormat<F> {
+    inner: F,
+}
+
+impl<F> ThreadEventFormat<F> {
+    /// Wraps the event format `inner`.
+    pub fn new(inner: F) -> ThreadEventFormat<F> {
+        ThreadEventFormat { inner }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for ThreadEventFormat<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let fields = ThreadFields::current();
+        write!(writer, "{} ", fields)?;
+        if let Some(span) = ctx.lookup_current() {
+            if let Some(threads) = span.extensions().get::<SpanThreads>() {
+                if threads.created.id != fields.id {
+                    write!(writer, "span.thread.id={} ", threads.created.id)?;
+                }
+            }
+        }
+        self.inner.format_event(ctx, writer, event)
+    }
+}
+
+#[test]
+fn thread_layer_records_hand_offs() {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use tracing_core::Dispatch;
+    use tracing_subscriber::fmt::format::Format;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let output = Output::default();
+    let writer = output.clone();
+    let subscriber = Registry::default().with(ThreadLayer::new()).with(
+        tracing_subscriber::fmt::layer()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .event_format(ThreadEventFormat::new(Format::default().without_time())),
+    );
+    let dispatch = Dispatch::new(subscriber);
+
+    let creator = ::current();
+    let span = tracing::dispatcher::with_default(&dispatch, || tracing::info_span!("work"));
+    let span_id = span.id().unwrap();
+    // Keep the span open, so its extensions can be inspected afterwards.
+    let _kept = span.clone();
+    let handle = thread::Builder::new().name("worker".to_string());
+    let worker_dispatch = dispatch.clone();
+    let worker = handle
+        .spawn(move || {
+            tracing::dispatcher::with_default(&worker_dispatch, || {
+                for _ in 0..2 {
+                    let _entered = span.enter();
+                }
+                span.in_scope(|| tracing::info!("handed off"));
+            });
+            ::current()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+
+    let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+    assert!(output.starts_with(&format!("thread.id={} ", worker)));
+    assert!(output.contains(&format!(
+        "thread.name=\"worker\" span.thread.id={} ",
+        creator
+    )));
+    assert!(output.trim_end().ends_with("handed off"));
+
+    let registry = dispatch.downcast_ref::<Registry>().unwrap();
+    let span = registry.span(&span_id).unwrap();
+    let extensions = span.extensions();
+    let threads = extensions.get::<SpanThreads>().unwrap();
+    assert_eq!(threads.created().id(), creator);
+    assert_eq!(threads.hand_offs().len(), 1);
+    assert_eq!(threads.hand_offs()[0].thread().id(), worker);
+    assert_eq!(threads.hand_offs()[0].thread().name(), Some("worker"));
+    assert_eq!(threads.hand_offs()[0].entries(), 3);
+}